@@ -0,0 +1,386 @@
+use std::str::from_utf8;
+use std::iter::Peekable;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+use bytes::Bytes;
+use serde_bytes::ByteBuf;
+use serde_bencode::value::Value;
+
+/// Maximum nesting of lists and dictionaries
+///
+/// The deeper values is rejected on read, so the values can be processed recursively.
+pub const MAX_DEPTH: usize = 64;
+
+/// Bencoded value which borrows the buffer it's read from
+///
+/// The lists and dictionaries is validated on read, but their items is read lazily.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BValue<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(BList<'a>),
+    Dict(BDict<'a>),
+}
+
+/// Items of list without the "l" and "e"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BList<'a>(&'a [u8]);
+
+/// Entries of dictionary without the "d" and "e"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BDict<'a>(&'a [u8]);
+
+fn read_int(buf: &[u8]) -> Option<(i64, &[u8])> {
+    let end = buf.iter().position(|&c| c == b'e')?;
+    let value = from_utf8(&buf[1..end]).ok()?.parse().ok()?;
+    Some((value, &buf[end + 1..]))
+}
+
+fn read_bytes(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let colon = buf.iter().position(|&c| c == b':')?;
+    let len: usize = from_utf8(&buf[..colon]).ok()?.parse().ok()?;
+    let rest = &buf[colon + 1..];
+    if len <= rest.len() {
+        Some((&rest[..len], &rest[len..]))
+    } else {
+        None
+    }
+}
+
+/// Split buffer to the raw value at start and the rest
+pub fn split_value(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    // iterative to not overflow stack on deeply nested input
+    let mut depth = 0usize;
+    let mut rest = buf;
+    loop {
+        rest = match *rest.first()? {
+            b'i' => read_int(rest)?.1,
+            b'l' | b'd' if depth < MAX_DEPTH => {
+                depth += 1;
+                &rest[1..]
+            },
+            b'e' if depth > 0 => {
+                depth -= 1;
+                &rest[1..]
+            },
+            c if c.is_ascii_digit() => read_bytes(rest)?.1,
+            _ => return None,
+        };
+        if depth == 0 {
+            let len = buf.len() - rest.len();
+            return Some((&buf[..len], rest));
+        }
+    }
+}
+
+/// Share the part of buffer without copying
+///
+/// The part must be borrowed from the buffer, e.g. the bytes value read from it.
+pub fn share(buf: &Bytes, part: &[u8]) -> Bytes {
+    let start = (part.as_ptr() as usize).checked_sub(buf.as_ptr() as usize)
+        .expect("The part isn't borrowed from buffer");
+    buf.slice(start, start + part.len())
+}
+
+/// Read value which takes the whole buffer
+pub fn decode(buf: &[u8]) -> Option<BValue<'_>> {
+    match split_value(buf)? {
+        (value, b"") => Some(read_value(value)),
+        _ => None,
+    }
+}
+
+// Read value which is already validated by split_value
+fn read_value(raw: &[u8]) -> BValue<'_> {
+    match raw[0] {
+        b'i' => BValue::Int(read_int(raw).unwrap().0),
+        b'l' => BValue::List(BList(&raw[1..raw.len() - 1])),
+        b'd' => BValue::Dict(BDict(&raw[1..raw.len() - 1])),
+        _ => BValue::Bytes(read_bytes(raw).unwrap().0),
+    }
+}
+
+impl<'a> BValue<'a> {
+    pub fn int(&self) -> Option<i64> {
+        match *self {
+            BValue::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            BValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn dict(&self) -> Option<BDict<'a>> {
+        match *self {
+            BValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Copy to owned value
+    pub fn to_value(&self) -> Value {
+        match *self {
+            BValue::Int(value) => Value::Int(value),
+            BValue::Bytes(bytes) => Value::Bytes(bytes.into()),
+            BValue::List(list) => Value::List(list.iter().map(|(item, _)| item.to_value()).collect()),
+            BValue::Dict(dict) => Value::Dict(dict.iter().map(|(key, value, _)| (key.into(), value.to_value())).collect()),
+        }
+    }
+}
+
+impl<'a> BList<'a> {
+    /// Items with their raw bencoded form
+    pub fn iter(&self) -> BListIter<'a> {
+        BListIter(self.0)
+    }
+}
+
+pub struct BListIter<'a>(&'a [u8]);
+
+impl<'a> Iterator for BListIter<'a> {
+    type Item = (BValue<'a>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (raw, rest) = split_value(self.0)?;
+        self.0 = rest;
+        Some((read_value(raw), raw))
+    }
+}
+
+impl<'a> BDict<'a> {
+    /// Entries with raw bencoded form of value
+    pub fn iter(&self) -> BDictIter<'a> {
+        BDictIter(self.0)
+    }
+
+    /// Value of the first entry with given key
+    pub fn get(&self, key: &[u8]) -> Option<BValue<'a>> {
+        self.get_raw(key).map(read_value)
+    }
+
+    /// Raw bencoded value of the first entry with given key
+    pub fn get_raw(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.iter().find(|&(entry_key, _, _)| entry_key == key).map(|(_, _, raw)| raw)
+    }
+}
+
+pub struct BDictIter<'a>(&'a [u8]);
+
+impl<'a> Iterator for BDictIter<'a> {
+    type Item = (&'a [u8], BValue<'a>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, rest) = read_bytes(self.0)?;
+        let (raw, rest) = split_value(rest)?;
+        self.0 = rest;
+        Some((key, read_value(raw), raw))
+    }
+}
+
+/// Bencode writer which appends to the given buffer
+///
+/// The dictionary keys should be written in sorted order.
+pub struct BWriter<'a>(&'a mut Vec<u8>);
+
+impl<'a> BWriter<'a> {
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        BWriter(buf)
+    }
+
+    pub fn int(&mut self, value: i64) -> &mut Self {
+        self.0.push(b'i');
+        self.0.extend(value.to_string().as_bytes());
+        self.0.push(b'e');
+        self
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.bytes_header(bytes.len());
+        self.0.extend(bytes);
+        self
+    }
+
+    /// Length prefix of byte string which content is appended to `buf()`
+    pub fn bytes_header(&mut self, len: usize) -> &mut Self {
+        self.0.extend(len.to_string().as_bytes());
+        self.0.push(b':');
+        self
+    }
+
+    pub fn list(&mut self) -> &mut Self {
+        self.0.push(b'l');
+        self
+    }
+
+    pub fn dict(&mut self) -> &mut Self {
+        self.0.push(b'd');
+        self
+    }
+
+    /// End of list or dictionary
+    pub fn end(&mut self) -> &mut Self {
+        self.0.push(b'e');
+        self
+    }
+
+    /// Already bencoded value
+    pub fn raw(&mut self, raw: &[u8]) -> &mut Self {
+        self.0.extend(raw);
+        self
+    }
+
+    pub fn value(&mut self, value: &Value) -> &mut Self {
+        match *value {
+            Value::Int(value) => self.int(value),
+            Value::Bytes(ref bytes) => self.bytes(bytes),
+            Value::List(ref list) => {
+                self.list();
+                for item in list {
+                    self.value(item);
+                }
+                self.end()
+            },
+            Value::Dict(ref dict) => {
+                let mut entries: Vec<_> = dict.iter().collect();
+                entries.sort_by_key(|&(key, _)| key);
+                self.dict();
+                for (key, value) in entries {
+                    self.bytes(key).value(value);
+                }
+                self.end()
+            },
+        }
+    }
+
+    /// Dictionary which is merged with the given extra entries
+    pub fn dict_with<'w, 'e>(&'w mut self, extra: &'e BTreeMap<ByteBuf, Value>) -> BDictWriter<'w, 'a, 'e> {
+        self.dict();
+        BDictWriter {
+            writer: self,
+            extra: extra.iter().peekable(),
+        }
+    }
+
+    pub fn buf(&mut self) -> &mut Vec<u8> {
+        self.0
+    }
+}
+
+/// Writer of dictionary entries which inserts the extra entries in order of keys
+///
+/// The extra entry is skipped when its key is written explicitly.
+pub struct BDictWriter<'w, 'a: 'w, 'e> {
+    writer: &'w mut BWriter<'a>,
+    extra: Peekable<btree_map::Iter<'e, ByteBuf, Value>>,
+}
+
+impl<'w, 'a, 'e> BDictWriter<'w, 'a, 'e> {
+    /// Write key of entry, the value should be written next
+    ///
+    /// The keys should be written in sorted order.
+    pub fn key(&mut self, key: &[u8]) -> &mut BWriter<'a> {
+        while let Some(&(extra_key, value)) = self.extra.peek() {
+            if &extra_key[..] > key {
+                break;
+            }
+            if &extra_key[..] < key {
+                self.writer.bytes(extra_key).value(value);
+            }
+            self.extra.next();
+        }
+        self.writer.bytes(key)
+    }
+
+    pub fn end(self) {
+        let BDictWriter {writer, extra} = self;
+        for (key, value) in extra {
+            writer.bytes(key).value(value);
+        }
+        writer.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use bytes::Bytes;
+    use serde_bytes::ByteBuf;
+    use serde_bencode::value::Value;
+    use super::{BValue, BWriter, MAX_DEPTH, decode, share, split_value};
+
+    #[test]
+    fn test_bencode_read() {
+        let buf = b"d1:ai-12e1:bl2:xyi0ee1:cdee";
+        let dict = decode(buf).unwrap().dict().unwrap();
+
+        assert_eq!(dict.get(b"a"), Some(BValue::Int(-12)));
+        assert_eq!(dict.get_raw(b"b"), Some(&b"l2:xyi0ee"[..]));
+        assert_eq!(dict.get(b"zzz"), None);
+        assert_eq!(dict.iter().map(|(key, _, _)| key).collect::<Vec<_>>(), vec![&b"a"[..], b"b", b"c"]);
+        match dict.get(b"b") {
+            Some(BValue::List(list)) => assert_eq!(list.iter().map(|(item, _)| item).collect::<Vec<_>>(),
+                                                   vec![BValue::Bytes(b"xy"), BValue::Int(0)]),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(split_value(b"4:spami1e"), Some((&b"4:spam"[..], &b"i1e"[..])));
+        // the trailing data, the truncated string and the unclosed list
+        assert_eq!(decode(b"i1ei2e"), None);
+        assert_eq!(decode(b"5:spam"), None);
+        assert_eq!(decode(b"li1e"), None);
+        assert_eq!(decode(b"i1x2e"), None);
+
+        // the nesting is limited
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), None);
+    }
+
+    #[test]
+    fn test_bencode_share() {
+        let buf = Bytes::from(&b"d5:token40:0123456789abcdefghij0123456789abcdefghije"[..]);
+        let token = decode(&buf).unwrap().dict().unwrap().get(b"token").unwrap().bytes().unwrap();
+
+        let shared = share(&buf, token);
+        assert_eq!(&shared[..], token);
+        assert_eq!(shared.as_ptr(), token.as_ptr());
+    }
+
+    #[test]
+    fn test_bencode_write() {
+        let mut buf = Vec::new();
+        BWriter::new(&mut buf).dict()
+            .bytes(b"a").int(-12)
+            .bytes(b"b").list().bytes(b"xy").raw(b"i0e").end()
+            .end();
+        assert_eq!(&buf[..], &b"d1:ai-12e1:bl2:xyi0eee"[..]);
+
+        let value = decode(&buf).unwrap().to_value();
+        let mut copy = Vec::new();
+        BWriter::new(&mut copy).value(&value);
+        assert_eq!(copy, buf);
+        let mut extra = BTreeMap::new();
+        extra.insert(ByteBuf::from(b"a".to_vec()), Value::Int(1));
+        extra.insert(ByteBuf::from(b"c".to_vec()), Value::Int(3));
+        extra.insert(ByteBuf::from(b"e".to_vec()), Value::Int(5));
+        let mut merged = Vec::new();
+        {
+            let mut writer = BWriter::new(&mut merged);
+            let mut dict = writer.dict_with(&extra);
+            dict.key(b"b").int(2);
+            dict.key(b"c").int(4);
+            dict.end();
+        }
+        assert_eq!(&merged[..], &b"d1:ai1e1:bi2e1:ci4e1:ei5ee"[..]);
+
+        assert_eq!(value, Value::Dict(vec![(b"a".to_vec(), Value::Int(-12)),
+                                           (b"b".to_vec(), Value::List(vec![Value::Bytes(b"xy".to_vec()), Value::Int(0)]))]
+                                      .into_iter().collect()));
+    }
+}
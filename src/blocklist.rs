@@ -0,0 +1,225 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::io::{BufRead, Result};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/// Minimal access level of eMule filter entry which isn't blocked
+pub const IPFILTER_LEVEL: u32 = 127;
+
+type KRange = (IpAddr, IpAddr);
+
+/// Set of blocked IP ranges
+///
+/// The list is shared between clones, so it can be updated at runtime
+/// while the services and routing tables use it.
+#[derive(Clone, Default)]
+pub struct KBlocklist(Rc<RefCell<Vec<KRange>>>);
+
+impl fmt::Debug for KBlocklist {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KBlocklist({} ranges)", self.len())
+    }
+}
+
+// Sort ranges and merge overlapping ones
+fn normalize(ranges: &mut Vec<KRange>) {
+    ranges.sort();
+    let mut merged: Vec<KRange> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges.drain(..) {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1 {
+                if end > last.1 {
+                    last.1 = end;
+                }
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+    *ranges = merged;
+}
+
+// Parse address allowing zero padded IPv4 octets like "001.002.003.004"
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse().ok();
+    }
+    let octets: Vec<u8> = s.split('.').filter_map(|octet| octet.parse().ok()).collect();
+    if octets.len() == 4 && s.split('.').count() == 4 {
+        Some(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])))
+    } else {
+        None
+    }
+}
+
+fn parse_range(s: &str) -> Option<KRange> {
+    let mut ips = s.splitn(2, '-');
+    let start = parse_ip(ips.next()?)?;
+    let end = parse_ip(ips.next()?)?;
+    if start.is_ipv4() == end.is_ipv4() && start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    line.is_empty() || line.starts_with('#') || line.starts_with("//")
+}
+
+/// Parse line of eMule ipfilter.dat: "start - end , level , description"
+///
+/// Returns None for comments, allowed ranges and malformed lines.
+fn parse_ipfilter_line(line: &str) -> Option<KRange> {
+    let mut fields = line.splitn(3, ',');
+    let range = parse_range(fields.next()?)?;
+    let level = match fields.next() {
+        Some(level) => level.trim().parse().ok()?,
+        None => 0,
+    };
+    if level < IPFILTER_LEVEL { Some(range) } else { None }
+}
+
+/// Parse line of PeerGuardian text format: "description:start-end"
+fn parse_p2p_line(line: &str) -> Option<KRange> {
+    let colon = line.rfind(':')?;
+    parse_range(&line[colon + 1..])
+}
+
+impl KBlocklist {
+    pub fn new() -> Self {
+        KBlocklist::default()
+    }
+
+    /// Block addresses from start to end inclusive
+    pub fn insert(&self, start: IpAddr, end: IpAddr) {
+        let mut ranges = self.0.borrow_mut();
+        ranges.push((start, end));
+        normalize(&mut ranges);
+    }
+
+    fn load<R, F>(&self, reader: R, parse: F) -> Result<usize>
+        where R: BufRead,
+              F: Fn(&str) -> Option<KRange>,
+    {
+        let mut loaded = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if is_comment(line) {
+                continue;
+            }
+            match parse(line) {
+                Some(range) => loaded.push(range),
+                None => debug!("Skip blocklist line: {}", line),
+            }
+        }
+        let count = loaded.len();
+        let mut ranges = self.0.borrow_mut();
+        ranges.extend(loaded);
+        normalize(&mut ranges);
+        Ok(count)
+    }
+
+    /// Add ranges from eMule ipfilter.dat
+    ///
+    /// The entries with access level `IPFILTER_LEVEL` and above is allowed, so they are skipped.
+    /// Returns number of added ranges.
+    pub fn load_ipfilter<R: BufRead>(&self, reader: R) -> Result<usize> {
+        self.load(reader, parse_ipfilter_line)
+    }
+
+    /// Add ranges from PeerGuardian P2P text format
+    ///
+    /// Returns number of added ranges.
+    pub fn load_p2p<R: BufRead>(&self, reader: R) -> Result<usize> {
+        self.load(reader, parse_p2p_line)
+    }
+
+    /// Replace ranges with ranges of another list
+    ///
+    /// Useful to reload the list at once.
+    pub fn replace(&self, other: &KBlocklist) {
+        let ranges = other.0.borrow().clone();
+        *self.0.borrow_mut() = ranges;
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// Number of ranges after merging of overlapping ones
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ranges = self.0.borrow();
+        // the last range which starts before address
+        let index = match ranges.binary_search_by(|&(start, _)| start.cmp(ip)) {
+            Ok(index) => index,
+            Err(0) => return false,
+            Err(index) => index - 1,
+        };
+        *ip <= ranges[index].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::KBlocklist;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_blocklist_ipfilter() {
+        let list = KBlocklist::new();
+        let data = b"# comment
+001.002.003.000 - 001.002.003.255 , 000 , Some range
+010.000.000.000 - 010.000.000.255 , 127 , Allowed range
+1.2.4.0 - 1.2.4.10 , 100 , Overlaps next
+1.2.4.5 - 1.2.4.20 , 0 , Overlaps previous
+garbage
+";
+        assert_eq!(list.load_ipfilter(&data[..]).unwrap(), 3);
+        assert_eq!(list.len(), 2);
+
+        assert!(list.contains(&ip("1.2.3.0")));
+        assert!(list.contains(&ip("1.2.3.255")));
+        assert!(list.contains(&ip("1.2.4.15")));
+        assert!(!list.contains(&ip("1.2.4.21")));
+        assert!(!list.contains(&ip("1.2.2.255")));
+        assert!(!list.contains(&ip("10.0.0.1")));
+        assert!(!list.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn test_blocklist_p2p() {
+        let list = KBlocklist::new();
+        let data = b"Some: org:1.2.3.0-1.2.3.255
+// comment
+Other:5.6.7.8-5.6.7.8
+";
+        assert_eq!(list.load_p2p(&data[..]).unwrap(), 2);
+        assert!(list.contains(&ip("1.2.3.100")));
+        assert!(list.contains(&ip("5.6.7.8")));
+        assert!(!list.contains(&ip("5.6.7.9")));
+
+        // update is visible through all clones
+        let shared = list.clone();
+        let update = KBlocklist::new();
+        update.insert(ip("9.9.9.0"), ip("9.9.9.255"));
+        list.replace(&update);
+        assert!(!shared.contains(&ip("1.2.3.100")));
+        assert!(shared.contains(&ip("9.9.9.9")));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures::{Future, IntoFuture, Stream};
+use futures::sync::{oneshot, mpsc};
+
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use super::{KError, KQueryArg, KQueryRes, KTransError, KCallOptions, KService};
+
+type KSyncResponder<Res> = oneshot::Sender<Result<Res, KTransError>>;
+struct KSyncCall<Arg, Res>(SocketAddr, Arg, KCallOptions, KSyncResponder<Res>);
+
+/// Thread-safe handle to make outgoing queries
+///
+/// The calls is forwarded to the reactor thread where the service is running,
+/// so this handle is a bit slower than `KService` itself.
+pub struct KSyncClient<Arg, Res> {
+    call_tx: mpsc::UnboundedSender<KSyncCall<Arg, Res>>,
+}
+
+impl<Arg, Res> Clone for KSyncClient<Arg, Res> {
+    fn clone(&self) -> Self {
+        KSyncClient { call_tx: self.call_tx.clone() }
+    }
+}
+
+impl<Arg, Res> KSyncClient<Arg, Res>
+    where Arg: 'static + Send,
+          Res: 'static + Send,
+{
+    /// Create thread-safe handle for the service
+    ///
+    /// The forwarding of calls is spawned using the given handle of reactor which runs the service.
+    pub fn new<Query, Handler>(service: &KService<Query, Arg, Res, Handler>, handle: &Handle) -> Self
+        where Query: 'static + Serialize + DeserializeOwned + Debug + Eq,
+              Arg: Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+              Res: Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+              Handler: 'static + Service<Request = Arg, Response = Res, Error = KError>,
+    {
+        let (call_tx, call_rx) = mpsc::unbounded();
+        let service = service.clone();
+        let spawn_handle = handle.clone();
+
+        handle.spawn(call_rx.for_each(move |KSyncCall(addr, arg, options, res_tx)| {
+            spawn_handle.spawn(service.call_with(addr, arg, options).then(|result| {
+                let _ = res_tx.send(result);
+                Ok(())
+            }));
+            Ok(())
+        }));
+
+        KSyncClient { call_tx }
+    }
+
+    pub fn call(&self, addr: SocketAddr, arg: Arg) -> impl Future<Item = Res, Error = KTransError> + Send {
+        self.call_with(addr, arg, KCallOptions::default())
+    }
+
+    /// Make outgoing query using specific options
+    pub fn call_with(&self, addr: SocketAddr, arg: Arg, options: KCallOptions) -> impl Future<Item = Res, Error = KTransError> + Send {
+        let (res_tx, res_rx) = oneshot::channel();
+        let sent = self.call_tx.unbounded_send(KSyncCall(addr, arg, options, res_tx));
+        // the both errors means that reactor is gone
+        sent.map_err(|_| KTransError::Shutdown).into_future()
+            .and_then(|_| res_rx.map_err(|_| KTransError::Shutdown))
+            .and_then(|result| result)
+    }
+}
+
+impl<Arg, Res> Service for KSyncClient<Arg, Res>
+    where Arg: 'static + Send,
+          Res: 'static + Send,
+{
+    type Request = (SocketAddr, Arg);
+    type Response = Res;
+    type Error = KTransError;
+    type Future = Box<dyn Future<Item = Res, Error = KTransError> + Send>;
+
+    fn call(&self, (addr, arg): Self::Request) -> Self::Future {
+        Box::new(KSyncClient::call(self, addr, arg))
+    }
+}
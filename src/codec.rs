@@ -2,21 +2,30 @@ use std::net::SocketAddr;
 use std::io::{Error, ErrorKind, Result};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::str::from_utf8;
 
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
+use log::Level;
 use hexdump::hexdump_iter;
 
 use serde_bencode::ser::to_bytes;
 use serde_bencode::de::from_bytes;
 
+use bytes::Bytes;
+
+use futures::future::Either;
+
 use tokio_core::net::UdpCodec;
 
-use super::{KMessage, KAddress, KTransId, KError, KQueryArg};
+use super::{KTransId, KVersion, KExtra, KError, KErrorKind, KQueryArg, KQueryRes};
+use super::bencode::{self, BValue, BWriter};
+use serde_extra::socket_addr;
 
 pub struct KCodec<Query, Arg, Res> {
-    phantom: PhantomData<(Query, Arg, Res)>,
+    names: KQueryNames<Query>,
+    phantom: PhantomData<(Arg, Res)>,
 }
 
 impl<Query, Arg, Res> KCodec<Query, Arg, Res>
@@ -26,6 +35,7 @@ impl<Query, Arg, Res> KCodec<Query, Arg, Res>
 {
     pub fn new() -> Self {
         KCodec {
+            names: KQueryNames::default(),
             phantom: PhantomData,
         }
     }
@@ -41,8 +51,85 @@ pub enum KData<Arg, Res> {
     Error(KError),
 }
 
+/// Message envelope data besides the payload
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KMeta {
+    /// Client version ("v" key)
+    pub version: Option<KVersion>,
+    /// Read-only node flag ("ro" key of query, BEP-43)
+    pub read_only: bool,
+    /// Address of receiver as seen by sender ("ip" key of response, BEP-42)
+    ///
+    /// It's set on decode only, the responses is sent with address of destination.
+    pub ip: Option<SocketAddr>,
+    /// Unknown keys of message which is sent back as is
+    pub extra: KExtra,
+}
+
 #[derive(Debug, Clone)]
-pub struct KItem<Arg, Res>(pub KId, pub KData<Arg, Res>);
+pub struct KItem<Arg, Res>(pub KId, pub KData<Arg, Res>, pub KMeta);
+
+/// Datagram of another protocol which shares socket with KRPC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KRaw(pub SocketAddr, pub Vec<u8>);
+
+/// Cheap check that datagram looks like KRPC message
+pub fn is_krpc(buf: &[u8]) -> bool {
+    buf.len() > 2 && buf[0] == b'd' && buf[buf.len() - 1] == b'e' &&
+        buf.windows(5).any(|key| key == b"1:y1:")
+}
+
+/// Names of query methods as they are sent
+///
+/// The name is converted with serde once per method and then looked up by either side.
+/// Only the names of known methods is kept, so the queries is expected to be a small set like enum.
+pub struct KQueryNames<Query>(Vec<(Query, String)>);
+
+impl<Query> Default for KQueryNames<Query> {
+    fn default() -> Self {
+        KQueryNames(Vec::new())
+    }
+}
+
+impl<Query: Serialize + DeserializeOwned + Eq> KQueryNames<Query> {
+    /// Query method of the given name if it is known
+    pub fn query(&mut self, name: &[u8]) -> Option<&Query> {
+        let index = match self.0.iter().position(|(_, known)| known.as_bytes() == name) {
+            Some(index) => index,
+            None => {
+                let name = from_utf8(name).ok()?;
+                let query = from_bytes(format!("{}:{}", name.len(), name).as_bytes()).ok()?;
+                self.0.push((query, name.to_string()));
+                self.0.len() - 1
+            },
+        };
+        Some(&self.0[index].0)
+    }
+
+    /// Name of the given query, None when it isn't serialized as string
+    pub fn name(&mut self, query: &Query) -> Option<&str> {
+        let index = match self.0.iter().position(|(known, _)| known == query) {
+            Some(index) => index,
+            None => {
+                let buf = to_bytes(query).ok()?;
+                let name = from_bytes(&buf).ok()?;
+                // the owned copy of query for the cache
+                self.0.push((from_bytes(&buf).ok()?, name));
+                self.0.len() - 1
+            },
+        };
+        Some(&self.0[index].1)
+    }
+}
+
+/// Transaction id and query method name of message which may be not fully decodable
+pub fn peek_header(buf: &[u8]) -> Option<(Option<KTransId>, Option<String>)> {
+    let dict = bencode::decode(buf)?.dict()?;
+    let tid = dict.get(b"t").and_then(|value| value.bytes()).map(KTransId::from);
+    let query = dict.get(b"q").and_then(|value| value.bytes())
+        .and_then(|name| from_utf8(name).ok()).map(String::from);
+    Some((tid, query))
+}
 
 impl<Arg, Res> Eq for KItem<Arg, Res> {}
 
@@ -52,52 +139,213 @@ impl<Arg, Res> PartialEq for KItem<Arg, Res> {
     }
 }
 
-impl<Query, Arg, Res> UdpCodec for KCodec<Query, Arg, Res>
+/// Error of message decoding
+#[derive(Debug)]
+pub enum KDecodeError {
+    /// The datagram isn't valid KRPC message
+    Malformed(String),
+    /// The response doesn't match the query of transaction
+    Response(KId, String),
+}
+
+impl From<KDecodeError> for Error {
+    fn from(error: KDecodeError) -> Self {
+        match error {
+            KDecodeError::Malformed(error) =>
+                Error::new(ErrorKind::InvalidData, format!("Decode error: {}", error)),
+            KDecodeError::Response(KId(addr, _), error) =>
+                Error::new(ErrorKind::InvalidData, format!("Unexpected response from {}: {}", addr, error)),
+        }
+    }
+}
+
+fn write_ip(writer: &mut BWriter, addr: &SocketAddr) {
+    writer.bytes_header(if addr.is_ipv4() { 6 } else { 18 });
+    socket_addr::to_bytes(writer.buf(), addr);
+}
+
+// The error is read leniently the same way as it's deserialized
+fn read_error(value: BValue) -> Option<KError> {
+    match value {
+        BValue::List(list) => {
+            let mut kind = None;
+            let mut message = None;
+            for (item, _) in list.iter() {
+                match item {
+                    BValue::Int(code) if kind.is_none() => kind = Some(KErrorKind::from(code)),
+                    BValue::Bytes(bytes) if message.is_none() => message = Some(bytes.to_vec()),
+                    _ => (),
+                }
+            }
+            Some(KError(kind.unwrap_or(KErrorKind::Generic), message.unwrap_or_default()))
+        },
+        BValue::Int(code) => Some(KError(KErrorKind::from(code), Vec::new())),
+        BValue::Bytes(message) => Some(KError(KErrorKind::Generic, message.to_vec())),
+        BValue::Dict(..) => None,
+    }
+}
+
+fn malformed<E: ToString>(error: E) -> KDecodeError {
+    KDecodeError::Malformed(error.to_string())
+}
+
+// Keys of message envelope by message type
+const QUERY_KEYS: &[&[u8]] = &[b"t", b"y", b"q", b"a", b"v", b"ro"];
+const RESPONSE_KEYS: &[&[u8]] = &[b"t", b"y", b"r", b"v", b"ip"];
+const ERROR_KEYS: &[&[u8]] = &[b"t", b"y", b"e", b"v", b"ip"];
+
+impl<Query, Arg, Res> KCodec<Query, Arg, Res>
     where Query: Serialize + DeserializeOwned + Debug + Eq,
           Arg: Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
-          Res: Serialize + DeserializeOwned + Debug,
+          Res: Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
 {
-    type In = KItem<Arg, Res>;
-    type Out = KItem<Arg, Res>;
+    /// Decode message using the query of transaction to decode response
+    ///
+    /// The given function finds the query which was sent in transaction.
+    /// When it's unknown the response is decoded without regard to query.
+    /// The transaction id and the byte strings of payload share the buffer without copying.
+    pub fn decode_for<'q, F>(&mut self, addr: &SocketAddr, buf: &Bytes, lookup: F) -> ::std::result::Result<Either<KItem<Arg, Res>, KRaw>, KDecodeError>
+        where F: FnOnce(&KId) -> Option<&'q Query>,
+              Query: 'q,
+    {
+        if log_enabled!(Level::Trace) {
+            trace!("recv from: {}, packet:", addr);
+            for line in hexdump_iter(buf) {
+                trace!("    {}", line);
+            }
+        }
+        if !is_krpc(buf) {
+            return Ok(Either::B(KRaw(*addr, buf.to_vec())));
+        }
+        let dict = bencode::decode(buf).and_then(|value| value.dict())
+            .ok_or_else(|| malformed("Invalid bencode"))?;
+        let kind = dict.get(b"y").and_then(|value| value.bytes());
+        let known = match kind {
+            Some(b"q") => QUERY_KEYS,
+            Some(b"r") => RESPONSE_KEYS,
+            Some(b"e") => ERROR_KEYS,
+            _ => return Err(malformed("Unknown message type")),
+        };
 
-    fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> Result<Self::In> {
-        trace!("recv from: {}, packet:", addr);
-        for line in hexdump_iter(buf) {
-            trace!("    {}", line);
+        let mut tid = None;
+        let mut payload = None;
+        let mut query = None;
+        let mut meta = KMeta::default();
+        for (key, value, raw) in dict.iter() {
+            if !known.contains(&key) {
+                meta.extra.insert(key.to_vec().into(), value.to_value());
+                continue;
+            }
+            match key {
+                b"t" => tid = Some(value.bytes().map(|tid| KTransId(bencode::share(buf, tid)))
+                                   .ok_or_else(|| malformed("Invalid transaction id"))?),
+                b"q" => query = Some(value.bytes().ok_or_else(|| malformed("Invalid method"))?),
+                b"a" | b"r" | b"e" => payload = Some((value, raw)),
+                b"v" => meta.version = Some(value.bytes().map(KVersion::from)
+                                            .ok_or_else(|| malformed("Invalid version"))?),
+                b"ro" => meta.read_only = value.int() == Some(1),
+                // the address is only a hint, so the malformed one doesn't fail the message
+                b"ip" => meta.ip = value.bytes().and_then(|bytes| socket_addr::from_bytes(bytes).ok()),
+                _ => (),
+            }
         }
-        let msg: KMessage<Query, Arg, Res> = from_bytes(buf)
-            .map_err(|err| Error::new(ErrorKind::InvalidData,
-                                      format!("Decode error: {}", err)))?;
-        debug!("recv from: {}, message: {:?}", addr, msg);
-        match msg {
-            KMessage::Query {tid, query, arg} => {
-                if arg.query() == query {
-                    Ok(KItem(KId(*addr, tid), KData::Query(arg)))
-                } else {
-                    Err(Error::new(ErrorKind::InvalidData,
-                                   "Malformed message"))
-                }
+        let (value, raw) = payload.ok_or_else(|| malformed("Missing payload"))?;
+        let id = KId(*addr, tid);
+
+        let data = match kind {
+            Some(b"q") => {
+                let name = query.ok_or_else(|| malformed("Missing method"))?;
+                let query = self.names.query(name).ok_or_else(|| malformed("Unsupported method"))?;
+                let arg = Arg::decode(query, &bencode::share(buf, raw)).map_err(malformed)?;
+                debug!("recv from: {}, query: {:?}", addr, arg);
+                KData::Query(arg)
             },
-            KMessage::Response {tid, res, ..} =>
-                Ok(KItem(KId(*addr, tid), KData::Response(res))),
-            KMessage::Error {tid, error, ..} =>
-                Ok(KItem(KId(*addr, tid), KData::Error(error))),
-        }
+            Some(b"r") => {
+                let payload = bencode::share(buf, raw);
+                let res = match lookup(&id) {
+                    Some(query) => match Res::decode(query, &payload) {
+                        Ok(res) => res,
+                        Err(err) => return Err(KDecodeError::Response(id, err.to_string())),
+                    },
+                    None => from_bytes(&payload).map_err(malformed)?,
+                };
+                debug!("recv from: {}, response: {:?}", addr, res);
+                KData::Response(res)
+            },
+            _ => {
+                let error = read_error(value).ok_or_else(|| malformed("Invalid error"))?;
+                debug!("recv from: {}, error: {:?}", addr, error);
+                KData::Error(error)
+            },
+        };
+        Ok(Either::A(KItem(id, data, meta)))
     }
+}
 
-    fn encode(&mut self, KItem(KId(addr, tid), msg): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
-        debug!("send to: {}, message: {:?}", addr, msg);
-        let msg = match msg {
-            KData::Query(arg) => KMessage::Query {tid, query: arg.query(), arg},
-            KData::Response(res) => KMessage::Response {ip: Some(KAddress(addr)), tid, res},
-            KData::Error(error) => KMessage::Error {ip: Some(KAddress(addr)), tid, error},
+impl<Query, Arg, Res> UdpCodec for KCodec<Query, Arg, Res>
+    where Query: Serialize + DeserializeOwned + Debug + Eq,
+          Arg: Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+{
+    type In = Either<KItem<Arg, Res>, KRaw>;
+    type Out = Either<KItem<Arg, Res>, KRaw>;
+
+    fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> Result<Self::In> {
+        self.decode_for(addr, &Bytes::from(buf), |_| None).map_err(Error::from)
+    }
+
+    fn encode(&mut self, item: Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        let KItem(KId(addr, tid), msg, KMeta {version: v, read_only: ro, extra, ..}) = match item {
+            Either::A(item) => item,
+            Either::B(KRaw(addr, buf)) => {
+                trace!("send raw to: {}, {} bytes", addr, buf.len());
+                into.extend(buf);
+                return addr;
+            },
         };
-        let buf = to_bytes(&msg).unwrap();
-        trace!("send to: {}, packet:", addr);
-        for line in hexdump_iter(&buf) {
-            trace!("    {}", line);
+        debug!("send to: {}, message: {:?}", addr, msg);
+        let start = into.len();
+        {
+            let mut writer = BWriter::new(into);
+            let mut dict = writer.dict_with(&extra);
+            let kind: &[u8] = match msg {
+                KData::Query(arg) => {
+                    arg.encode(dict.key(b"a").buf());
+                    match self.names.name(&arg.query()) {
+                        Some(name) => { dict.key(b"q").bytes(name.as_bytes()); },
+                        None => warn!("Query without name: {:?}", arg),
+                    }
+                    if ro {
+                        dict.key(b"ro").int(1);
+                    }
+                    b"q"
+                },
+                KData::Response(res) => {
+                    write_ip(dict.key(b"ip"), &addr);
+                    res.encode(dict.key(b"r").buf());
+                    b"r"
+                },
+                KData::Error(KError(kind, message)) => {
+                    dict.key(b"e").list().int(kind.code()).bytes(&message).end();
+                    write_ip(dict.key(b"ip"), &addr);
+                    b"e"
+                },
+            };
+            if let Some(KTransId(tid)) = tid {
+                dict.key(b"t").bytes(&tid);
+            }
+            if let Some(KVersion(v)) = v {
+                dict.key(b"v").bytes(&v);
+            }
+            dict.key(b"y").bytes(kind);
+            dict.end();
+        }
+        if log_enabled!(Level::Trace) {
+            trace!("send to: {}, packet:", addr);
+            for line in hexdump_iter(&into[start..]) {
+                trace!("    {}", line);
+            }
         }
-        into.extend(buf);
         addr
     }
 }
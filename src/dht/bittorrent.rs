@@ -4,13 +4,24 @@ use serde::de::{Deserialize, Deserializer};
 use std::net::SocketAddr;
 use std::str::from_utf8;
 
-use rpc::KQueryArg;
+use bytes::Bytes;
+
+use serde_bencode;
+use serde::de::Error as DeError;
+use serde_bencode::Error;
+
+use rpc::{KExtra, KQueryArg, KQueryRes};
+use bencode::{self, BValue, BWriter};
 use serde_extra::{socket_addr, option_bool};
 
 use super::id::Sha1Id;
+use super::id::sha1::serde_hash;
+use super::table::RoutingTable;
 
 pub type BtDhtId = Sha1Id;
 
+pub type BtDhtTable = RoutingTable<BtDhtId>;
+
 #[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BtDhtQuery {
     #[serde(rename = "ping")]
@@ -40,30 +51,37 @@ impl<'de> Deserialize<'de> for BtDhtQuery {
     }
 }
 
-pub type BtDhtToken = Vec<u8>;
+pub type BtDhtToken = Bytes;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum BtDhtArg {
     AnnouncePeer {
         id: BtDhtId,
-        #[serde(with = "option_bool")]
+        #[serde(default, with = "option_bool")]
         implied_port: bool,
         info_hash: BtDhtId,
         port: u16,
-        #[serde(with = "serde_bytes")]
         token: BtDhtToken,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     GetPeers {
         id: BtDhtId,
         info_hash: BtDhtId,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     FindNode {
         id: BtDhtId,
         target: BtDhtId,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     Ping {
         id: BtDhtId,
+        #[serde(flatten)]
+        extra: KExtra,
     },
 }
 
@@ -77,34 +95,283 @@ impl KQueryArg for BtDhtArg {
             &BtDhtArg::AnnouncePeer {..} => BtDhtQuery::AnnouncePeer,
         }
     }
+
+    fn decode(query: &BtDhtQuery, buf: &Bytes) -> Result<Self, serde_bencode::Error> {
+        Ok(match *query {
+            BtDhtQuery::Ping => {
+                let fields = BtDhtFields::read(buf, &[b"id"])?;
+                BtDhtArg::Ping {id: fields.id("id")?, extra: fields.extra}
+            },
+            BtDhtQuery::FindNode => {
+                let fields = BtDhtFields::read(buf, &[b"id", b"target"])?;
+                BtDhtArg::FindNode {id: fields.id("id")?, target: fields.id("target")?, extra: fields.extra}
+            },
+            BtDhtQuery::GetPeers => {
+                let fields = BtDhtFields::read(buf, &[b"id", b"info_hash"])?;
+                BtDhtArg::GetPeers {id: fields.id("id")?, info_hash: fields.id("info_hash")?, extra: fields.extra}
+            },
+            BtDhtQuery::AnnouncePeer => {
+                let fields = BtDhtFields::read(buf, &[b"id", b"implied_port", b"info_hash", b"port", b"token"])?;
+                let port = fields.int("port")?.ok_or_else(|| missing_field("port"))?;
+                if !(0..=0xffff).contains(&port) {
+                    return Err(Error::custom("Invalid port"));
+                }
+                BtDhtArg::AnnouncePeer {
+                    id: fields.id("id")?,
+                    implied_port: fields.int("implied_port")? == Some(1),
+                    info_hash: fields.id("info_hash")?,
+                    port: port as u16,
+                    token: fields.shared("token")?.ok_or_else(|| missing_field("token"))?,
+                    extra: fields.extra,
+                }
+            },
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut writer = BWriter::new(buf);
+        match *self {
+            BtDhtArg::Ping {ref id, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                dict.end();
+            },
+            BtDhtArg::FindNode {ref id, ref target, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                dict.key(b"target").bytes(target.as_ref());
+                dict.end();
+            },
+            BtDhtArg::GetPeers {ref id, ref info_hash, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                dict.key(b"info_hash").bytes(info_hash.as_ref());
+                dict.end();
+            },
+            BtDhtArg::AnnouncePeer {ref id, implied_port, ref info_hash, port, ref token, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                if implied_port {
+                    dict.key(b"implied_port").int(1);
+                }
+                dict.key(b"info_hash").bytes(info_hash.as_ref());
+                dict.key(b"port").int(port as i64);
+                dict.key(b"token").bytes(token);
+                dict.end();
+            },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum BtDhtRes {
-    GetPeersNodes {
-        id: BtDhtId,
-        #[serde(with = "serde_bytes")]
-        token: BtDhtToken,
-        #[serde(with = "nodes_info")]
-        nodes: BtDhtNodesInfo,
-    },
-    GetPeersValues {
+    /// The closest nodes and the known peers, any of them may be missing
+    GetPeers {
         id: BtDhtId,
-        #[serde(with = "serde_bytes")]
         token: BtDhtToken,
-        values: BtDhtPeersInfo,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "nodes_info::option")]
+        nodes: Option<BtDhtNodesInfo>,
+        /// The nodes with IPv6 addresses (BEP-32)
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "nodes6_info::option")]
+        nodes6: Option<BtDhtNodesInfo>,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "peers_info::option")]
+        values: Option<BtDhtPeersInfo>,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     FindNode {
         id: BtDhtId,
         #[serde(with = "nodes_info")]
         nodes: BtDhtNodesInfo,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     Pong {
         id: BtDhtId,
+        #[serde(flatten)]
+        extra: KExtra,
     },
 }
 
+impl BtDhtRes {
+    /// All nodes of response both IPv4 and IPv6 ones
+    pub fn nodes<'a>(&'a self) -> impl Iterator<Item = &'a BtDhtNodeInfo> + 'a {
+        let (nodes, nodes6) = match *self {
+            BtDhtRes::GetPeers {ref nodes, ref nodes6, ..} => (nodes.as_ref(), nodes6.as_ref()),
+            BtDhtRes::FindNode {ref nodes, ..} => (Some(nodes), None),
+            BtDhtRes::Pong {..} => (None, None),
+        };
+        nodes.into_iter().chain(nodes6).flat_map(|nodes| nodes.iter())
+    }
+
+    /// The peers of get_peers response
+    pub fn values(&self) -> &[BtDhtPeerInfo] {
+        match *self {
+            BtDhtRes::GetPeers {values: Some(ref values), ..} => values,
+            _ => &[],
+        }
+    }
+}
+
+impl KQueryRes for BtDhtRes {
+    type Query = BtDhtQuery;
+
+    fn decode(query: &BtDhtQuery, buf: &Bytes) -> Result<Self, serde_bencode::Error> {
+        Ok(match *query {
+            BtDhtQuery::Ping | BtDhtQuery::AnnouncePeer => {
+                let fields = BtDhtFields::read(buf, &[b"id"])?;
+                BtDhtRes::Pong {id: fields.id("id")?, extra: fields.extra}
+            },
+            BtDhtQuery::FindNode => {
+                let fields = BtDhtFields::read(buf, &[b"id", b"nodes"])?;
+                BtDhtRes::FindNode {
+                    id: fields.id("id")?,
+                    nodes: fields.nodes("nodes", 6)?.ok_or_else(|| missing_field("nodes"))?,
+                    extra: fields.extra,
+                }
+            },
+            BtDhtQuery::GetPeers => {
+                let fields = BtDhtFields::read(buf, &[b"id", b"nodes", b"nodes6", b"token", b"values"])?;
+                BtDhtRes::GetPeers {
+                    id: fields.id("id")?,
+                    token: fields.shared("token")?.ok_or_else(|| missing_field("token"))?,
+                    nodes: fields.nodes("nodes", 6)?,
+                    nodes6: fields.nodes("nodes6", 18)?,
+                    values: fields.peers("values")?,
+                    extra: fields.extra,
+                }
+            },
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut writer = BWriter::new(buf);
+        match *self {
+            BtDhtRes::GetPeers {ref id, ref token, ref nodes, ref nodes6, ref values, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                if let Some(ref nodes) = *nodes {
+                    write_nodes(dict.key(b"nodes"), nodes);
+                }
+                if let Some(ref nodes6) = *nodes6 {
+                    write_nodes(dict.key(b"nodes6"), nodes6);
+                }
+                dict.key(b"token").bytes(token);
+                if let Some(ref values) = *values {
+                    let writer = dict.key(b"values").list();
+                    for peer_info in values {
+                        writer.bytes_header(compact_addr_len(&peer_info.addr));
+                        socket_addr::to_bytes(writer.buf(), &peer_info.addr);
+                    }
+                    writer.end();
+                }
+                dict.end();
+            },
+            BtDhtRes::FindNode {ref id, ref nodes, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                write_nodes(dict.key(b"nodes"), nodes);
+                dict.end();
+            },
+            BtDhtRes::Pong {ref id, ref extra} => {
+                let mut dict = writer.dict_with(extra);
+                dict.key(b"id").bytes(id.as_ref());
+                dict.end();
+            },
+        }
+    }
+}
+
+fn missing_field(key: &'static str) -> Error {
+    Error::missing_field(key)
+}
+
+// Fields of argument or response dictionary which borrows the datagram
+//
+// The entries with unknown keys is copied to extra.
+struct BtDhtFields<'a> {
+    buf: &'a Bytes,
+    fields: Vec<(&'a [u8], BValue<'a>)>,
+    extra: KExtra,
+}
+
+impl<'a> BtDhtFields<'a> {
+    fn read(buf: &'a Bytes, known: &[&[u8]]) -> Result<Self, Error> {
+        let dict = bencode::decode(buf).and_then(|value| value.dict())
+            .ok_or_else(|| Error::custom("Invalid dictionary"))?;
+        let mut fields = Vec::with_capacity(known.len());
+        let mut extra = KExtra::new();
+        for (key, value, _) in dict.iter() {
+            if known.contains(&key) {
+                fields.push((key, value));
+            } else {
+                extra.insert(key.to_vec().into(), value.to_value());
+            }
+        }
+        Ok(BtDhtFields {buf, fields, extra})
+    }
+
+    fn get(&self, key: &str) -> Option<BValue<'a>> {
+        self.fields.iter().find(|&&(field, _)| field == key.as_bytes()).map(|&(_, value)| value)
+    }
+
+    fn int(&self, key: &str) -> Result<Option<i64>, Error> {
+        match self.get(key) {
+            Some(value) => value.int().map(Some).ok_or_else(|| Error::custom(format!("Invalid {}", key))),
+            None => Ok(None),
+        }
+    }
+
+    fn bytes(&self, key: &str) -> Result<Option<&'a [u8]>, Error> {
+        match self.get(key) {
+            Some(value) => value.bytes().map(Some).ok_or_else(|| Error::custom(format!("Invalid {}", key))),
+            None => Ok(None),
+        }
+    }
+
+    // The bytes sharing the buffer of message
+    fn shared(&self, key: &str) -> Result<Option<Bytes>, Error> {
+        Ok(self.bytes(key)?.map(|bytes| bencode::share(self.buf, bytes)))
+    }
+
+    fn id(&self, key: &'static str) -> Result<BtDhtId, Error> {
+        let bytes = self.bytes(key)?.ok_or_else(|| missing_field(key))?;
+        if bytes.len() != 20 {
+            return Err(Error::custom(format!("Invalid {}", key)));
+        }
+        let mut hash = [0u8; 20];
+        hash.clone_from_slice(bytes);
+        Ok(BtDhtId::from(hash))
+    }
+
+    fn nodes(&self, key: &str, addr_len: usize) -> Result<Option<BtDhtNodesInfo>, Error> {
+        match self.bytes(key)? {
+            Some(bytes) => nodes_from_bytes(bytes, addr_len).map(Some)
+                .ok_or_else(|| Error::custom("Malformed compact node info")),
+            None => Ok(None),
+        }
+    }
+
+    // The malformed entries is skipped
+    fn peers(&self, key: &str) -> Result<Option<BtDhtPeersInfo>, Error> {
+        let list = match self.get(key) {
+            Some(BValue::List(list)) => list,
+            Some(..) => return Err(Error::custom(format!("Invalid {}", key))),
+            None => return Ok(None),
+        };
+        Ok(Some(list.iter().filter_map(|(value, _)| {
+            match value.bytes().and_then(|bytes| socket_addr::from_bytes(bytes).ok()) {
+                Some(addr) => Some(BtDhtPeerInfo {addr}),
+                None => {
+                    debug!("Skip malformed compact peer info: {:?}", value);
+                    None
+                },
+            }
+        }).collect()))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BtDhtNodeInfo {
     pub id: BtDhtId,
@@ -113,63 +380,199 @@ pub struct BtDhtNodeInfo {
 
 pub type BtDhtNodesInfo = Vec<BtDhtNodeInfo>;
 
-mod nodes_info {
-    use super::{BtDhtId, BtDhtNodeInfo, BtDhtNodesInfo};
+fn compact_addr_len(addr: &SocketAddr) -> usize {
+    if addr.is_ipv4() { 6 } else { 18 }
+}
+
+// Compact node info is 20 bytes of id followed by address
+fn nodes_to_bytes(nodes_info: &BtDhtNodesInfo) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for node_info in nodes_info {
+        serde_hash::to_bytes(&mut buf, node_info.id.as_ref());
+        socket_addr::to_bytes(&mut buf, &node_info.addr);
+    }
+    buf
+}
+
+fn write_nodes(writer: &mut BWriter, nodes_info: &BtDhtNodesInfo) {
+    let len = nodes_info.iter().map(|node_info| 20 + compact_addr_len(&node_info.addr)).sum();
+    writer.bytes_header(len);
+    for node_info in nodes_info {
+        serde_hash::to_bytes(writer.buf(), node_info.id.as_ref());
+        socket_addr::to_bytes(writer.buf(), &node_info.addr);
+    }
+}
+
+fn nodes_from_bytes(buf: &[u8], addr_len: usize) -> Option<BtDhtNodesInfo> {
+    let len = 20 + addr_len;
+    if buf.len() % len != 0 {
+        return None;
+    }
+    let mut nodes_info = Vec::new();
+    for buf in buf.chunks(len) {
+        let mut hash = [0u8; 20];
+        hash.clone_from_slice(&buf[..20]);
+        let id = BtDhtId::from(hash);
+        let addr = socket_addr::from_bytes(&buf[20..]).unwrap();
+        nodes_info.push(BtDhtNodeInfo {id, addr});
+    }
+    Some(nodes_info)
+}
+
+macro_rules! compact_nodes_module {
+    ($name:ident, $addr_len:expr) => {
+        mod $name {
+            use super::{BtDhtNodesInfo, nodes_to_bytes, nodes_from_bytes};
+            use serde_bytes;
+            use serde::ser::Serializer;
+            use serde::de::{Deserializer, Error};
+
+            pub fn serialize<S>(nodes_info: &BtDhtNodesInfo, serializer: S) -> Result<S::Ok, S::Error>
+                where S: Serializer
+            {
+                serializer.serialize_bytes(&nodes_to_bytes(nodes_info))
+            }
+
+            pub fn deserialize<'de, D>(deserializer: D) -> Result<BtDhtNodesInfo, D::Error>
+                where D: Deserializer<'de>
+            {
+                let buf: Vec<u8> = serde_bytes::deserialize(deserializer)?;
+                nodes_from_bytes(&buf, $addr_len).ok_or_else(|| Error::custom("Malformed compact node info"))
+            }
+
+            pub mod option {
+                use super::super::BtDhtNodesInfo;
+                use serde::ser::Serializer;
+                use serde::de::Deserializer;
+
+                pub fn serialize<S>(nodes_info: &Option<BtDhtNodesInfo>, serializer: S) -> Result<S::Ok, S::Error>
+                    where S: Serializer
+                {
+                    match *nodes_info {
+                        Some(ref nodes_info) => super::serialize(nodes_info, serializer),
+                        None => serializer.serialize_none(),
+                    }
+                }
+
+                pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<BtDhtNodesInfo>, D::Error>
+                    where D: Deserializer<'de>
+                {
+                    super::deserialize(deserializer).map(Some)
+                }
+            }
+        }
+    };
+}
+
+// The "nodes" with IPv4 addresses
+compact_nodes_module!(nodes_info, 6);
+// The "nodes6" of BEP-32 with IPv6 addresses
+compact_nodes_module!(nodes6_info, 18);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtDhtPeerInfo {
+    pub addr: SocketAddr,
+}
+
+/// The "values" is list of compact addresses, 6 bytes for IPv4 and 18 bytes for IPv6
+pub type BtDhtPeersInfo = Vec<BtDhtPeerInfo>;
+
+// Serde encoding of "values"
+mod peers_info {
+    use super::{BtDhtPeerInfo, BtDhtPeersInfo};
     use super::socket_addr;
-    use serde_bytes;
-    use serde::ser::Serializer;
-    use serde::de::{Deserializer, Error};
-    use super::super::id::sha1::serde_hash;
-    
-    pub fn serialize<S>(nodes_info: &BtDhtNodesInfo, serializer: S) -> Result<S::Ok, S::Error>
+    use serde_bytes::{Bytes, ByteBuf};
+    use serde::ser::{Serializer, SerializeSeq};
+    use serde::de::{Deserialize, Deserializer};
+
+    pub fn serialize<S>(peers_info: &BtDhtPeersInfo, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer
     {
-        let mut buf = Vec::new();
-        for node_info in nodes_info {
-            serde_hash::to_bytes(&mut buf, node_info.id.as_ref());
-            socket_addr::to_bytes(&mut buf, &node_info.addr);
+        let mut seq = serializer.serialize_seq(Some(peers_info.len()))?;
+        for peer_info in peers_info {
+            let mut buf = Vec::new();
+            socket_addr::to_bytes(&mut buf, &peer_info.addr);
+            seq.serialize_element(&Bytes::new(&buf))?;
         }
-        serializer.serialize_bytes(&buf)
+        seq.end()
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<BtDhtNodesInfo, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BtDhtPeersInfo, D::Error>
         where D: Deserializer<'de>
     {
-        let buf: Vec<u8> = serde_bytes::deserialize(deserializer)?;
-        let len = buf.len();
-        if len % 26 == 0 {
-            let mut nodes_info = Vec::new();
-            for buf in buf.chunks(26) {
-                let mut hash = [0u8; 20];
-                hash.clone_from_slice(&buf[..20]);
-                let id = BtDhtId::from(hash);
-                let addr = socket_addr::from_bytes(&buf[20..]).unwrap();
-                nodes_info.push(BtDhtNodeInfo {id, addr});
+        let list: Vec<ByteBuf> = Vec::deserialize(deserializer)?;
+        Ok(list.iter().filter_map(|buf| match socket_addr::from_bytes(buf) {
+            Ok(addr) => Some(BtDhtPeerInfo {addr}),
+            Err(_) => {
+                debug!("Skip malformed compact peer info of {} bytes", buf.len());
+                None
+            },
+        }).collect())
+    }
+
+    pub mod option {
+        use super::super::BtDhtPeersInfo;
+        use serde::ser::Serializer;
+        use serde::de::Deserializer;
+
+        pub fn serialize<S>(peers_info: &Option<BtDhtPeersInfo>, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer
+        {
+            match *peers_info {
+                Some(ref peers_info) => super::serialize(peers_info, serializer),
+                None => serializer.serialize_none(),
             }
-            Ok(nodes_info)
-        } else {
-            Err(Error::custom("Malformed compact node info"))
         }
-    }
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct BtDhtPeerInfo {
-    #[serde(with = "socket_addr")]
-    pub addr: SocketAddr,
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<BtDhtPeersInfo>, D::Error>
+            where D: Deserializer<'de>
+        {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
 }
 
-pub type BtDhtPeersInfo = Vec<BtDhtPeerInfo>;
-
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+    use bytes::Bytes;
     use serde_bencode::ser::{to_bytes};
     use serde_bencode::de::{from_bytes};
+    use serde_bencode::value::Value;
+    use serde::Deserialize;
+    use serde::de::IntoDeserializer;
+    use serde::de::value::Error as ValueError;
     use hexdump::hexdump;
-    use rpc::{KAddress, KMessage, KError, KErrorKind};
-    use super::{BtDhtQuery, BtDhtArg, BtDhtRes};
+    use futures::future::Either;
+    use tokio_core::net::UdpCodec;
+    use test::{black_box, Bencher};
+    use codec::{KCodec, KQueryNames, KItem, KId, KData, KMeta};
+    use rpc::{KAddress, KExtra, KMessage, KError, KErrorKind, KQueryArg, KQueryRes};
+    use super::{BtDhtQuery, BtDhtArg, BtDhtRes, BtDhtNodeInfo, BtDhtPeerInfo};
 
     type BtDhtMessage = KMessage<BtDhtQuery, BtDhtArg, BtDhtRes>;
+    type BtDhtItem = KItem<BtDhtArg, BtDhtRes>;
+
+    fn encode(item: BtDhtItem) -> Vec<u8> {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let mut buf = Vec::new();
+        codec.encode(Either::A(item), &mut buf);
+        buf
+    }
+
+    // Decode the response as sent to the given query
+    fn decode(buf: &[u8], query: BtDhtQuery) -> BtDhtItem {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        match codec.decode_for(&addr, &Bytes::from(buf), |_| Some(&query)).unwrap() {
+            Either::A(item) => item,
+            Either::B(..) => unreachable!(),
+        }
+    }
+
+    fn item_id(addr: &str, tid: &str) -> KId {
+        KId(addr.parse().unwrap(), Some(tid.into()))
+    }
 
     #[test]
     pub fn test_serde_ping_query() {
@@ -178,7 +581,11 @@ mod tests {
             query: BtDhtQuery::Ping,
             arg: BtDhtArg::Ping {
                 id: "0123456789abcdefghij".into(),
+                extra: KExtra::new(),
             },
+            v: None,
+            ro: false,
+            extra: KExtra::new(),
         };
 
         let ping_query_enc = to_bytes(&ping_query).unwrap();
@@ -195,7 +602,7 @@ mod tests {
 
         //assert!(false);
     }
-    
+
     #[test]
     pub fn test_serde_ping_response() {
         let ping_response: BtDhtMessage = KMessage::Response {
@@ -204,7 +611,10 @@ mod tests {
             tid: Some("aa".into()),
             res: BtDhtRes::Pong {
                 id: "0123456789abcdefghij".into(),
+                extra: KExtra::new(),
             },
+            v: None,
+            extra: KExtra::new(),
         };
 
         let ping_response_enc = to_bytes(&ping_response).unwrap();
@@ -226,6 +636,8 @@ mod tests {
             ip: None,
             tid: Some("55".into()),
             error: KError(KErrorKind::Method, "Unsupported method".into()),
+            v: None,
+            extra: KExtra::new(),
         };
 
         let method_error_enc = to_bytes(&method_error).unwrap();
@@ -240,4 +652,412 @@ mod tests {
         println!("method_error dec: {:?}", method_error_dec);
         assert_eq!(method_error_dec, method_error);
     }
+
+    #[test]
+    pub fn test_serde_error_kind() {
+        let kind = |value: u64| KErrorKind::deserialize(value.into_deserializer()) as Result<_, ValueError>;
+
+        assert_eq!(kind(204).unwrap(), KErrorKind::Method);
+        assert_eq!(kind(i64::MAX as u64).unwrap(), KErrorKind::Other(i64::MAX));
+        // the code doesn't wrap around to negative one
+        assert!(kind(u64::MAX).is_err());
+    }
+
+    #[test]
+    pub fn test_ping_query() {
+        let ping_query_enc = encode(KItem(item_id("1.2.3.4:6881", "aa"), KData::Query(ping_arg()), KMeta::default()));
+
+        println!("ping_query enc:");
+        hexdump(&ping_query_enc);
+
+        assert_eq!(&ping_query_enc[..], &b"d1:ad2:id20:0123456789abcdefghije1:q4:ping1:t2:aa1:y1:qe"[..]);
+
+        let KItem(id, data, meta) = decode(&ping_query_enc, BtDhtQuery::Ping);
+        assert_eq!(id, item_id("1.2.3.4:6881", "aa"));
+        match data {
+            KData::Query(arg) => assert_eq!(arg, ping_arg()),
+            data => panic!("Unexpected data: {:?}", data),
+        }
+        assert_eq!(meta, KMeta::default());
+    }
+
+    #[test]
+    pub fn test_ping_query_flags() {
+        let meta = KMeta {version: Some("KR01".into()), read_only: true, ..KMeta::default()};
+        let ping_query_enc = encode(KItem(item_id("1.2.3.4:6881", "aa"), KData::Query(ping_arg()), meta.clone()));
+
+        assert_eq!(&ping_query_enc[..], &b"d1:ad2:id20:0123456789abcdefghije1:q4:ping2:roi1e1:t2:aa1:v4:KR011:y1:qe"[..]);
+
+        assert_eq!(decode(&ping_query_enc, BtDhtQuery::Ping).2, meta);
+    }
+
+    #[test]
+    pub fn test_ping_response() {
+        let pong = BtDhtRes::Pong {id: "0123456789abcdefghij".into(), extra: KExtra::new()};
+        let ping_response_enc = encode(KItem(item_id("1.2.3.4:56789", "aa"), KData::Response(pong.clone()), KMeta::default()));
+
+        println!("ping_response enc:");
+        hexdump(&ping_response_enc);
+
+        assert_eq!(&ping_response_enc[..], &b"d2:ip6:\x01\x02\x03\x04\xdd\xd51:rd2:id20:0123456789abcdefghije1:t2:aa1:y1:re"[..]);
+
+        let KItem(_, data, meta) = decode(&ping_response_enc, BtDhtQuery::Ping);
+        match data {
+            KData::Response(res) => assert_eq!(res, pong),
+            data => panic!("Unexpected data: {:?}", data),
+        }
+        assert_eq!(meta.ip, Some("1.2.3.4:56789".parse().unwrap()));
+    }
+
+    #[test]
+    pub fn test_ping_response_v6() {
+        let pong = BtDhtRes::Pong {id: "0123456789abcdefghij".into(), extra: KExtra::new()};
+        let addr: SocketAddr = "[fd00::1234]:56789".parse().unwrap();
+        let ping_response_enc = encode(KItem(KId(addr, Some("aa".into())), KData::Response(pong), KMeta::default()));
+
+        assert_eq!(&ping_response_enc[..26], b"d2:ip18:\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x12\x34\xdd\xd5");
+
+        assert_eq!(decode(&ping_response_enc, BtDhtQuery::Ping).2.ip, Some(addr));
+    }
+
+    #[test]
+    pub fn test_method_error() {
+        let error = KError(KErrorKind::Method, "Unsupported method".into());
+        let method_error_enc = encode(KItem(item_id("1.2.3.4:6881", "55"), KData::Error(error.clone()), KMeta::default()));
+
+        println!("method_error enc:");
+        hexdump(&method_error_enc);
+
+        assert_eq!(&method_error_enc[..], &b"d1:eli204e18:Unsupported methode2:ip6:\x01\x02\x03\x04\x1a\xe11:t2:551:y1:ee"[..]);
+
+        match decode(&method_error_enc, BtDhtQuery::Ping).1 {
+            KData::Error(decoded) => assert_eq!(decoded, error),
+            data => panic!("Unexpected data: {:?}", data),
+        }
+    }
+
+    #[test]
+    pub fn test_decode_response_by_query() {
+        // the token makes it look like a get_peers response
+        let find_node_res = b"d2:id20:0123456789abcdefghij5:nodes0:5:token2:aae";
+
+        let res = BtDhtRes::decode(&BtDhtQuery::FindNode, &Bytes::from_static(find_node_res)).unwrap();
+        let mut extra = KExtra::new();
+        extra.insert(b"token".to_vec().into(), Value::Bytes(b"aa".to_vec()));
+        assert_eq!(res, BtDhtRes::FindNode {
+            id: "0123456789abcdefghij".into(),
+            nodes: Vec::new(),
+            extra,
+        });
+
+        let res = BtDhtRes::decode(&BtDhtQuery::GetPeers, &Bytes::from_static(find_node_res)).unwrap();
+        assert_eq!(res, BtDhtRes::GetPeers {
+            id: "0123456789abcdefghij".into(),
+            token: Bytes::from_static(b"aa"),
+            nodes: Some(Vec::new()),
+            nodes6: None,
+            values: None,
+            extra: KExtra::new(),
+        });
+
+        // the pong doesn't have nodes
+        assert!(BtDhtRes::decode(&BtDhtQuery::FindNode, &Bytes::from_static(b"d2:id20:0123456789abcdefghije")).is_err());
+    }
+
+    #[test]
+    pub fn test_decode_argument_by_query() {
+        // the unknown "want" key of BEP-32
+        let get_peers_arg = b"d2:id20:0123456789abcdefghij9:info_hash20:mnopqrstuvwxyz1234564:wantl2:n4ee";
+
+        let arg = BtDhtArg::decode(&BtDhtQuery::GetPeers, &Bytes::from_static(get_peers_arg)).unwrap();
+        let mut extra = KExtra::new();
+        extra.insert(b"want".to_vec().into(), Value::List(vec![Value::Bytes(b"n4".to_vec())]));
+        assert_eq!(arg, BtDhtArg::GetPeers {
+            id: "0123456789abcdefghij".into(),
+            info_hash: "mnopqrstuvwxyz123456".into(),
+            extra,
+        });
+
+        // the implied_port is optional
+        let announce_arg = b"d2:id20:0123456789abcdefghij9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token2:aae";
+
+        let arg = BtDhtArg::decode(&BtDhtQuery::AnnouncePeer, &Bytes::from_static(announce_arg)).unwrap();
+        assert_eq!(arg, BtDhtArg::AnnouncePeer {
+            id: "0123456789abcdefghij".into(),
+            implied_port: false,
+            info_hash: "mnopqrstuvwxyz123456".into(),
+            port: 6881,
+            token: Bytes::from_static(b"aa"),
+            extra: KExtra::new(),
+        });
+
+        let mut announce_arg_enc = Vec::new();
+        arg.encode(&mut announce_arg_enc);
+        assert_eq!(&announce_arg_enc[..], &announce_arg[..]);
+
+        assert!(BtDhtArg::decode(&BtDhtQuery::FindNode, &Bytes::from_static(get_peers_arg)).is_err());
+        // the port out of range
+        assert!(BtDhtArg::decode(&BtDhtQuery::AnnouncePeer, &Bytes::from_static(b"d2:id20:0123456789abcdefghij9:info_hash20:mnopqrstuvwxyz1234564:porti65536e5:token2:aae")).is_err());
+    }
+
+    #[test]
+    pub fn test_get_peers_response() {
+        let node = BtDhtNodeInfo {id: "0123456789abcdefghij".into(), addr: "1.2.3.4:6881".parse().unwrap()};
+        let node6 = BtDhtNodeInfo {id: "mnopqrstuvwxyz123456".into(), addr: "[fd00::1]:6881".parse().unwrap()};
+        let peer = BtDhtPeerInfo {addr: "5.6.7.8:51413".parse().unwrap()};
+        let get_peers_res = BtDhtRes::GetPeers {
+            id: "0123456789abcdefghij".into(),
+            token: Bytes::from_static(b"aa"),
+            nodes: Some(vec![node.clone()]),
+            nodes6: Some(vec![node6.clone()]),
+            values: Some(vec![peer.clone()]),
+            extra: KExtra::new(),
+        };
+
+        let mut get_peers_res_enc = Vec::new();
+        get_peers_res.encode(&mut get_peers_res_enc);
+        let get_peers_res_dec = BtDhtRes::decode(&BtDhtQuery::GetPeers, &Bytes::from(get_peers_res_enc.clone())).unwrap();
+        assert_eq!(get_peers_res_dec, get_peers_res);
+
+        assert_eq!(get_peers_res_dec.nodes().collect::<Vec<_>>(), vec![&node, &node6]);
+        assert_eq!(get_peers_res_dec.values(), &[peer][..]);
+
+        // the IPv4 nodes in "nodes6"
+        let bad_nodes6 = b"d2:id20:0123456789abcdefghij6:nodes626:0123456789abcdefghij\x01\x02\x03\x04\x1a\xe15:token2:aae";
+        assert!(BtDhtRes::decode(&BtDhtQuery::GetPeers, &Bytes::from_static(bad_nodes6)).is_err());
+    }
+
+    #[test]
+    pub fn test_compact_peers_info() {
+        let get_peers_res = BtDhtRes::GetPeers {
+            id: "0123456789abcdefghij".into(),
+            token: Bytes::from_static(b"aa"),
+            nodes: None,
+            nodes6: None,
+            values: Some(vec![
+                BtDhtPeerInfo {addr: "1.2.3.4:6881".parse().unwrap()},
+                BtDhtPeerInfo {addr: "[fd00::1]:6881".parse().unwrap()},
+            ]),
+            extra: KExtra::new(),
+        };
+
+        let mut get_peers_res_enc = Vec::new();
+        get_peers_res.encode(&mut get_peers_res_enc);
+        assert_eq!(&get_peers_res_enc[..], &b"d2:id20:0123456789abcdefghij5:token2:aa6:valuesl6:\x01\x02\x03\x04\x1a\xe118:\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1ee"[..]);
+        assert_eq!(BtDhtRes::decode(&BtDhtQuery::GetPeers, &Bytes::from(get_peers_res_enc.clone())).unwrap(), get_peers_res);
+
+        // the entry of 5 bytes is skipped
+        let res = BtDhtRes::decode(&BtDhtQuery::GetPeers, &Bytes::from_static(b"d2:id20:0123456789abcdefghij5:token2:aa6:valuesl5:\x01\x02\x03\x04\x1a6:\x01\x02\x03\x04\x1a\xe1ee")).unwrap();
+        assert_eq!(res.values(), &[BtDhtPeerInfo {addr: "1.2.3.4:6881".parse().unwrap()}][..]);
+    }
+
+    #[test]
+    pub fn test_extra_round_trip() {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        // the unknown keys of both the message and the argument
+        let query = b"d1:ad2:id20:0123456789abcdefghij6:vendori1ee1:q4:ping1:t2:aa1:y1:q1:zd1:xi2eee";
+
+        let item = match codec.decode(&addr, query).unwrap() {
+            Either::A(item) => item,
+            Either::B(..) => unreachable!(),
+        };
+        match item {
+            KItem(_, KData::Query(BtDhtArg::Ping {ref extra, ..}), ref meta) => {
+                assert_eq!(extra.get(&b"vendor".to_vec().into()), Some(&Value::Int(1)));
+                assert!(meta.extra.contains_key(&b"z".to_vec().into()));
+            },
+            _ => unreachable!(),
+        }
+
+        let mut buf = Vec::new();
+        codec.encode(Either::A(item), &mut buf);
+        assert_eq!(&buf[..], &query[..]);
+    }
+
+    #[test]
+    pub fn test_malformed_ip() {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        // the address of 5 bytes
+        let response = b"d2:ip5:\x01\x02\x03\x04\x1a1:rd2:id20:0123456789abcdefghije1:t2:aa1:y1:re";
+
+        match codec.decode_for(&addr, &Bytes::from_static(response), |_| Some(&BtDhtQuery::Ping)).unwrap() {
+            Either::A(KItem(_, KData::Response(BtDhtRes::Pong {..}), meta)) => assert_eq!(meta.ip, None),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    pub fn test_lenient_error() {
+        fn decode_error(buf: &[u8]) -> KError {
+            match decode(buf, BtDhtQuery::Ping).1 {
+                KData::Error(error) => error,
+                data => panic!("Unexpected data: {:?}", data),
+            }
+        }
+
+        // the unknown code
+        let error = decode_error(b"d1:eli299e6:Custome1:t2:551:y1:ee");
+        assert_eq!(error, KError(KErrorKind::Other(299), b"Custom".to_vec()));
+        assert_eq!(&encode(KItem(item_id("1.2.3.4:6881", "55"), KData::Error(error), KMeta::default()))[..],
+                   &b"d1:eli299e6:Custome2:ip6:\x01\x02\x03\x04\x1a\xe11:t2:551:y1:ee"[..]);
+
+        // the code only, the message only and the extra items
+        assert_eq!(decode_error(b"d1:eli202ee1:t2:551:y1:ee"), KError(KErrorKind::Server, Vec::new()));
+        assert_eq!(decode_error(b"d1:el4:Oopse1:t2:551:y1:ee"), KError(KErrorKind::Generic, b"Oops".to_vec()));
+        assert_eq!(decode_error(b"d1:eli203e3:Badi1ee1:t2:551:y1:ee"), KError(KErrorKind::Protocol, b"Bad".to_vec()));
+
+        // the message isn't UTF-8
+        let error = decode_error(b"d1:eli201e2:\xff\xfee1:t2:551:y1:ee");
+        assert_eq!(error.1, vec![0xff, 0xfe]);
+        assert_eq!(error.message(), "\u{fffd}\u{fffd}");
+    }
+
+    #[test]
+    pub fn test_query_names() {
+        let mut names = KQueryNames::default();
+        assert_eq!(names.name(&BtDhtQuery::AnnouncePeer), Some("announce_peer"));
+        assert_eq!(names.query(b"get_peers"), Some(&BtDhtQuery::GetPeers));
+        assert_eq!(names.query(b"vote"), None);
+        assert_eq!(names.name(&BtDhtQuery::GetPeers), Some("get_peers"));
+
+        // the unknown method fails the query
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        assert!(codec.decode(&addr, b"d1:ad2:id20:0123456789abcdefghije1:q4:vote1:t2:aa1:y1:qe").is_err());
+    }
+
+    #[test]
+    pub fn test_untyped_response() {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+
+        // the response is decoded without query when it's read from framed socket
+        match codec.decode(&addr, b"d1:rd2:id20:0123456789abcdefghije1:t2:aa1:y1:re").unwrap() {
+            Either::A(KItem(id, KData::Response(BtDhtRes::Pong {..}), _)) => assert_eq!(id, item_id("1.2.3.4:6881", "aa")),
+            _ => unreachable!(),
+        }
+    }
+
+    // The encoding of codec is the same as of serde
+    #[test]
+    pub fn test_codec_encode() {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        let meta = KMeta {version: Some("KR01".into()), read_only: true, ..KMeta::default()};
+
+        let mut buf = Vec::new();
+        codec.encode(Either::A(KItem(KId(addr, Some("aa".into())), KData::Query(ping_arg()), meta.clone())), &mut buf);
+        assert_eq!(buf, to_bytes(&BtDhtMessage::Query {
+            tid: Some("aa".into()),
+            query: BtDhtQuery::Ping,
+            arg: ping_arg(),
+            v: Some("KR01".into()),
+            ro: true,
+            extra: KExtra::new(),
+        }).unwrap());
+
+        let mut buf = Vec::new();
+        codec.encode(Either::A(KItem(KId(addr, Some("aa".into())), KData::Response(get_peers_res()), meta.clone())), &mut buf);
+        assert_eq!(buf, to_bytes(&BtDhtMessage::Response {
+            ip: Some(KAddress(addr)),
+            tid: Some("aa".into()),
+            res: get_peers_res(),
+            v: Some("KR01".into()),
+            extra: KExtra::new(),
+        }).unwrap());
+
+        let mut buf = Vec::new();
+        let error = KError(KErrorKind::Method, "Unsupported method".into());
+        codec.encode(Either::A(KItem(KId(addr, None), KData::Error(error.clone()), KMeta::default())), &mut buf);
+        assert_eq!(buf, to_bytes(&BtDhtMessage::Error {
+            ip: Some(KAddress(addr)),
+            tid: None,
+            error,
+            v: None,
+            extra: KExtra::new(),
+        }).unwrap());
+    }
+
+    fn ping_arg() -> BtDhtArg {
+        BtDhtArg::Ping {
+            id: "0123456789abcdefghij".into(),
+            extra: KExtra::new(),
+        }
+    }
+
+    fn get_peers_res() -> BtDhtRes {
+        BtDhtRes::GetPeers {
+            id: "0123456789abcdefghij".into(),
+            token: Bytes::from_static(b"0123456789"),
+            nodes: Some((0..8).map(|n| BtDhtNodeInfo {
+                id: [n as u8; 20].into(),
+                addr: format!("10.0.0.{}:6881", n).parse().unwrap(),
+            }).collect()),
+            nodes6: None,
+            values: Some((0..8).map(|n| BtDhtPeerInfo {
+                addr: format!("10.0.1.{}:6881", n).parse().unwrap(),
+            }).collect()),
+            extra: KExtra::new(),
+        }
+    }
+
+    fn get_peers_item() -> BtDhtItem {
+        KItem(item_id("1.2.3.4:6881", "aa"), KData::Response(get_peers_res()),
+              KMeta {version: Some("KR01".into()), ..KMeta::default()})
+    }
+
+    fn get_peers_msg() -> BtDhtMessage {
+        KMessage::Response {
+            ip: Some(KAddress("1.2.3.4:6881".parse().unwrap())),
+            tid: Some("aa".into()),
+            res: get_peers_res(),
+            v: Some("KR01".into()),
+            extra: KExtra::new(),
+        }
+    }
+
+    #[bench]
+    pub fn bench_decode_codec(b: &mut Bencher) {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let addr = "1.2.3.4:6881".parse().unwrap();
+        let buf = Bytes::from(encode(get_peers_item()));
+
+        b.iter(|| {
+            codec.decode_for(&addr, black_box(&buf), |_| Some(&BtDhtQuery::GetPeers)).unwrap()
+        });
+    }
+
+    #[bench]
+    pub fn bench_decode_serde(b: &mut Bencher) {
+        let buf = to_bytes(&get_peers_msg()).unwrap();
+
+        b.iter(|| {
+            from_bytes::<BtDhtMessage>(black_box(&buf)).unwrap()
+        });
+    }
+
+    #[bench]
+    pub fn bench_encode_codec(b: &mut Bencher) {
+        let mut codec: KCodec<BtDhtQuery, BtDhtArg, BtDhtRes> = KCodec::new();
+        let item = get_peers_item();
+        let mut buf = Vec::with_capacity(1500);
+
+        b.iter(|| {
+            buf.clear();
+            codec.encode(Either::A(black_box(item.clone())), &mut buf);
+        });
+    }
+
+    #[bench]
+    pub fn bench_encode_serde(b: &mut Bencher) {
+        let msg = get_peers_msg();
+        let mut buf = Vec::with_capacity(1500);
+
+        b.iter(|| {
+            buf.clear();
+            buf.extend(to_bytes(&black_box(msg.clone())).unwrap());
+        });
+    }
 }
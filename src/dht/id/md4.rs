@@ -7,7 +7,7 @@ use crypto_hashes::md4::Md4;
 
 use super::NodeId;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Md4Id(
     #[serde(with = "serde_hash")]
     [u8; 16]
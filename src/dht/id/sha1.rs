@@ -7,7 +7,7 @@ use crypto_hashes::sha1::Sha1;
 
 use super::NodeId;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Sha1Id(
     #[serde(with = "serde_hash")]
     [u8; 20]
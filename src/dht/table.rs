@@ -0,0 +1,299 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ops::BitXor;
+use std::collections::{BTreeMap, HashMap};
+
+use blocklist::KBlocklist;
+use limit::mask_ip;
+
+use super::id::NodeId;
+
+/// Default number of nodes in bucket (the K of Kademlia)
+pub const DEFAULT_BUCKET_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutingNode<Id> {
+    pub id: Id,
+    pub addr: SocketAddr,
+}
+
+/// Reason why node isn't inserted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingError {
+    /// The id is the own id of table
+    OwnId,
+    /// The address is in blocklist
+    Blocked,
+    /// The bucket of node is full
+    Full,
+    /// Too many nodes of the same subnet
+    SubnetLimit,
+    /// Another node has the same address
+    AddrInUse,
+}
+
+/// Diversity limits which make it harder to fill the table with nodes of single party
+#[derive(Debug, Clone)]
+pub struct RoutingLimits {
+    /// Maximum number of nodes of single subnet in each bucket
+    pub bucket_subnet: usize,
+    /// Maximum number of nodes of single subnet in whole table
+    pub table_subnet: usize,
+    /// Prefix length of IPv4 subnet
+    pub v4_prefix: u8,
+    /// Prefix length of IPv6 subnet
+    pub v6_prefix: u8,
+}
+
+impl Default for RoutingLimits {
+    fn default() -> Self {
+        RoutingLimits {
+            bucket_subnet: 2,
+            table_subnet: 10,
+            v4_prefix: 24,
+            v6_prefix: 64,
+        }
+    }
+}
+
+impl RoutingLimits {
+    fn subnet(&self, addr: &SocketAddr) -> IpAddr {
+        let prefix = match *addr {
+            SocketAddr::V4(..) => self.v4_prefix,
+            SocketAddr::V6(..) => self.v6_prefix,
+        };
+        mask_ip(&addr.ip(), prefix)
+    }
+}
+
+/// Kademlia routing table
+///
+/// The nodes is kept in buckets by length of common prefix with own id.
+/// Each address may be used by only one node.
+pub struct RoutingTable<Id> {
+    own_id: Id,
+    bucket_size: usize,
+    buckets: BTreeMap<usize, Vec<RoutingNode<Id>>>,
+    blocklist: Option<KBlocklist>,
+    limits: RoutingLimits,
+    // number of nodes by subnet
+    subnets: HashMap<IpAddr, usize>,
+    addrs: HashMap<SocketAddr, Id>,
+}
+
+impl<Id> RoutingTable<Id>
+    where Id: NodeId + Copy + PartialEq,
+{
+    pub fn new(own_id: Id) -> Self {
+        RoutingTable {
+            own_id,
+            bucket_size: DEFAULT_BUCKET_SIZE,
+            buckets: BTreeMap::new(),
+            blocklist: None,
+            limits: RoutingLimits::default(),
+            subnets: HashMap::new(),
+            addrs: HashMap::new(),
+        }
+    }
+
+    pub fn with_bucket_size(mut self, bucket_size: usize) -> Self {
+        self.bucket_size = bucket_size;
+        self
+    }
+
+    /// Reject nodes which addresses is in the given list
+    pub fn with_blocklist(mut self, blocklist: KBlocklist) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Set limits of nodes per subnet
+    ///
+    /// The limits is checked on insert, so they should be set before the nodes is added.
+    pub fn with_limits(mut self, limits: RoutingLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn own_id(&self) -> &Id {
+        &self.own_id
+    }
+
+    /// Add node or update address of known node
+    pub fn insert(&mut self, id: Id, addr: SocketAddr) -> Result<(), RoutingError> {
+        if id == self.own_id {
+            return Err(RoutingError::OwnId);
+        }
+        if self.blocklist.as_ref().map(|blocklist| blocklist.contains(&addr.ip())).unwrap_or(false) {
+            return Err(RoutingError::Blocked);
+        }
+        let old_addr = match self.get(&id) {
+            Some(node) if node.addr == addr => return Ok(()),
+            Some(node) => node.addr,
+            None => return self.insert_new(id, addr),
+        };
+        // the new address should fit the limits as well
+        self.remove(&id);
+        match self.insert_new(id, addr) {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                // restore the node with the old address
+                let _ = self.insert_new(id, old_addr);
+                Err(error)
+            },
+        }
+    }
+
+    fn insert_new(&mut self, id: Id, addr: SocketAddr) -> Result<(), RoutingError> {
+        if self.addrs.contains_key(&addr) {
+            return Err(RoutingError::AddrInUse);
+        }
+        let subnet = self.limits.subnet(&addr);
+        if self.subnets.get(&subnet).cloned().unwrap_or(0) >= self.limits.table_subnet {
+            return Err(RoutingError::SubnetLimit);
+        }
+        let index = self.own_id.equal_bits(&id);
+        if let Some(bucket) = self.buckets.get(&index) {
+            if bucket.len() >= self.bucket_size {
+                return Err(RoutingError::Full);
+            }
+            let limits = &self.limits;
+            if bucket.iter().filter(|node| limits.subnet(&node.addr) == subnet).count() >= limits.bucket_subnet {
+                return Err(RoutingError::SubnetLimit);
+            }
+        }
+        self.buckets.entry(index).or_default().push(RoutingNode {id, addr});
+        self.addrs.insert(addr, id);
+        *self.subnets.entry(subnet).or_insert(0) += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &Id) -> Option<RoutingNode<Id>> {
+        let index = self.own_id.equal_bits(id);
+        let node = {
+            let bucket = self.buckets.get_mut(&index)?;
+            let position = bucket.iter().position(|node| node.id == *id)?;
+            bucket.remove(position)
+        };
+        if self.buckets[&index].is_empty() {
+            self.buckets.remove(&index);
+        }
+        self.addrs.remove(&node.addr);
+        let subnet = self.limits.subnet(&node.addr);
+        if let Some(count) = self.subnets.get_mut(&subnet) {
+            *count -= 1;
+        }
+        if self.subnets.get(&subnet) == Some(&0) {
+            self.subnets.remove(&subnet);
+        }
+        Some(node)
+    }
+
+    pub fn get(&self, id: &Id) -> Option<&RoutingNode<Id>> {
+        self.buckets.get(&self.own_id.equal_bits(id))
+            .and_then(|bucket| bucket.iter().find(|node| node.id == *id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a RoutingNode<Id>> + 'a {
+        self.buckets.values().flat_map(|bucket| bucket.iter())
+    }
+
+    /// Nodes which is closest to target by XOR distance
+    pub fn closest(&self, target: &Id, count: usize) -> Vec<RoutingNode<Id>>
+        where Id: BitXor<Output = Id> + Ord
+    {
+        let mut nodes: Vec<_> = self.iter().cloned().collect();
+        nodes.sort_by_key(|node| *target ^ node.id);
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use blocklist::KBlocklist;
+    use super::super::id::Sha1Id;
+    use super::{RoutingTable, RoutingError, RoutingLimits};
+
+    fn id(first: u8) -> Sha1Id {
+        let mut bytes = [0u8; 20];
+        bytes[0] = first;
+        Sha1Id::from(bytes)
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_table_insert() {
+        let blocklist = KBlocklist::new();
+        let mut table = RoutingTable::new(id(0)).with_bucket_size(2).with_blocklist(blocklist.clone());
+
+        assert_eq!(table.insert(id(0), addr("10.0.0.1:6881")), Err(RoutingError::OwnId));
+        // the same bucket of ids with the first bit set
+        assert_eq!(table.insert(id(0x80), addr("10.0.0.1:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x81), addr("10.0.0.2:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x82), addr("10.0.0.3:6881")), Err(RoutingError::Full));
+        assert_eq!(table.insert(id(0x01), addr("10.0.0.3:6881")), Ok(()));
+        // update of known node
+        assert_eq!(table.insert(id(0x80), addr("10.0.0.4:6881")), Ok(()));
+        assert_eq!(table.get(&id(0x80)).unwrap().addr, addr("10.0.0.4:6881"));
+        assert_eq!(table.len(), 3);
+
+        blocklist.insert("10.0.1.0".parse().unwrap(), "10.0.1.255".parse().unwrap());
+        assert_eq!(table.insert(id(0x40), addr("10.0.1.1:6881")), Err(RoutingError::Blocked));
+
+        assert_eq!(table.remove(&id(0x81)).unwrap().addr, addr("10.0.0.2:6881"));
+        assert_eq!(table.insert(id(0x82), addr("10.0.0.5:6881")), Ok(()));
+
+        // the nodes with the same common prefix is ordered by the rest of distance
+        assert_eq!(table.insert(id(0x07), addr("10.0.0.6:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x06), addr("10.0.0.7:6881")), Ok(()));
+
+        assert_eq!(table.closest(&id(0x03), 1)[0].id, id(0x01));
+        assert_eq!(table.closest(&id(0x04), 2).iter().map(|node| node.id).collect::<Vec<_>>(), vec![id(0x06), id(0x07)]);
+        assert_eq!(table.closest(&id(0x03), 10).len(), 5);
+    }
+
+    #[test]
+    fn test_table_limits() {
+        let mut table = RoutingTable::new(id(0)).with_limits(RoutingLimits {
+            bucket_subnet: 2,
+            table_subnet: 3,
+            ..RoutingLimits::default()
+        });
+
+        assert_eq!(table.insert(id(0x80), addr("10.0.0.1:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x81), addr("10.0.0.1:6881")), Err(RoutingError::AddrInUse));
+        assert_eq!(table.insert(id(0x81), addr("10.0.0.1:6882")), Ok(()));
+        // bucket limit of /24
+        assert_eq!(table.insert(id(0x82), addr("10.0.0.2:6881")), Err(RoutingError::SubnetLimit));
+        assert_eq!(table.insert(id(0x82), addr("10.0.1.2:6881")), Ok(()));
+        // another bucket
+        assert_eq!(table.insert(id(0x40), addr("10.0.0.3:6881")), Ok(()));
+        // table limit of /24
+        assert_eq!(table.insert(id(0x20), addr("10.0.0.4:6881")), Err(RoutingError::SubnetLimit));
+
+        // moving to the full subnet keeps the old address
+        assert_eq!(table.insert(id(0x82), addr("10.0.0.5:6881")), Err(RoutingError::SubnetLimit));
+        assert_eq!(table.get(&id(0x82)).unwrap().addr, addr("10.0.1.2:6881"));
+
+        table.remove(&id(0x80));
+        assert_eq!(table.insert(id(0x20), addr("10.0.0.4:6881")), Ok(()));
+
+        // the /64 of IPv6
+        assert_eq!(table.insert(id(0x10), addr("[2001:db8::1]:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x11), addr("[2001:db8::2]:6881")), Ok(()));
+        assert_eq!(table.insert(id(0x12), addr("[2001:db8::3]:6881")), Err(RoutingError::SubnetLimit));
+        assert_eq!(table.insert(id(0x12), addr("[2001:db8:0:1::3]:6881")), Ok(()));
+    }
+}
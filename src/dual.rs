@@ -0,0 +1,144 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures::Future;
+use futures::future::Either;
+
+use net2::UdpBuilder;
+
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+use super::{KError, KQueryArg, KQueryRes, KTransError, KOptions, KCallOptions, KService};
+use super::transport::{KTransport, KUdpTransport};
+
+/// Address family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KFamily {
+    V4,
+    V6,
+}
+
+impl KFamily {
+    pub fn of(addr: &SocketAddr) -> Self {
+        match *addr {
+            SocketAddr::V4(..) => KFamily::V4,
+            SocketAddr::V6(..) => KFamily::V6,
+        }
+    }
+}
+
+/// Handler of one family which passes queries to the shared handler
+///
+/// The shared handler takes pair of the family which query arrived on and query argument.
+pub struct KFamilyHandler<Arg, Handler> {
+    family: KFamily,
+    handler: Rc<Handler>,
+    phantom: PhantomData<Arg>,
+}
+
+impl<Arg, Handler> Service for KFamilyHandler<Arg, Handler>
+    where Handler: Service<Request = (KFamily, Arg)>,
+{
+    type Request = Arg;
+    type Response = Handler::Response;
+    type Error = Handler::Error;
+    type Future = Handler::Future;
+
+    fn call(&self, arg: Arg) -> Self::Future {
+        self.handler.call((self.family, arg))
+    }
+}
+
+type KFamilyService<Query, Arg, Res, Handler> = KService<Query, Arg, Res, KFamilyHandler<Arg, Handler>>;
+
+/// Service which works over IPv4 and IPv6 together
+///
+/// Each family has own transport, the outgoing calls is routed by the family of destination address.
+pub struct KDualService<Query, Arg, Res, Handler> {
+    v4: KFamilyService<Query, Arg, Res, Handler>,
+    v6: KFamilyService<Query, Arg, Res, Handler>,
+}
+
+impl<Query, Arg, Res, Handler> Clone for KDualService<Query, Arg, Res, Handler> {
+    fn clone(&self) -> Self {
+        KDualService { v4: self.v4.clone(), v6: self.v6.clone() }
+    }
+}
+
+impl<'s, Query, Arg, Res, Handler> KDualService<Query, Arg, Res, Handler>
+    where Query: 's + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 's + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: 's + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+          Handler: 's + Service<Request = (KFamily, Arg), Response = Res, Error = KError>,
+{
+    /// Create service bound to the given IPv4 and IPv6 addresses
+    ///
+    /// The IPv6 socket doesn't accept IPv4 datagrams, so the both families can use the same port.
+    pub fn bind(handler: Handler, v4_addr: &SocketAddr, v6_addr: &SocketAddr, handle: &Handle, options: KOptions) -> Result<(Self, impl Future<Item = (), Error = Error> + 's), Error> {
+        let v4_transport = KUdpTransport::bind(v4_addr, handle)?;
+        let v6_socket = UdpBuilder::new_v6()?.only_v6(true)?.bind(v6_addr)?;
+        let v6_transport = KUdpTransport::from_socket(v6_socket, handle)?;
+        KDualService::with_transports(handler, v4_transport, v6_transport, handle, options)
+    }
+
+    /// Create service which runs over the given transports
+    pub fn with_transports<V4Transport, V6Transport>(handler: Handler, v4_transport: V4Transport, v6_transport: V6Transport, handle: &Handle, options: KOptions) -> Result<(Self, impl Future<Item = (), Error = Error> + 's), Error>
+        where V4Transport: 's + KTransport,
+              V6Transport: 's + KTransport,
+    {
+        let handler = Rc::new(handler);
+        let (v4, v4_server) = KService::with_transport(KFamilyHandler {
+            family: KFamily::V4,
+            handler: handler.clone(),
+            phantom: PhantomData,
+        }, v4_transport, handle, options.clone())?;
+        let (v6, v6_server) = KService::with_transport(KFamilyHandler {
+            family: KFamily::V6,
+            handler,
+            phantom: PhantomData,
+        }, v6_transport, handle, options)?;
+        Ok((KDualService { v4, v6 }, v4_server.join(v6_server).map(|_| ())))
+    }
+
+    /// The service of given family
+    pub fn service(&self, family: KFamily) -> &KFamilyService<Query, Arg, Res, Handler> {
+        match family {
+            KFamily::V4 => &self.v4,
+            KFamily::V6 => &self.v6,
+        }
+    }
+
+    pub fn local_addr(&self, family: KFamily) -> SocketAddr {
+        self.service(family).local_addr()
+    }
+
+    /// Number of outstanding transactions of both families
+    pub fn active(&self) -> usize {
+        self.v4.active() + self.v6.active()
+    }
+
+    /// Stop the both services
+    pub fn shutdown(&self) {
+        self.v4.shutdown();
+        self.v6.shutdown();
+    }
+
+    pub fn call(&self, addr: SocketAddr, arg: Arg) -> impl Future<Item = Res, Error = KTransError> {
+        self.call_with(addr, arg, KCallOptions::default())
+    }
+
+    /// Make outgoing query using specific options
+    pub fn call_with(&self, addr: SocketAddr, arg: Arg, options: KCallOptions) -> impl Future<Item = Res, Error = KTransError> {
+        match KFamily::of(&addr) {
+            KFamily::V4 => Either::A(self.v4.call_with(addr, arg, options)),
+            KFamily::V6 => Either::B(self.v6.call_with(addr, arg, options)),
+        }
+    }
+}
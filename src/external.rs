@@ -0,0 +1,109 @@
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
+
+/// Options of external address voting
+#[derive(Debug, Clone)]
+pub struct KVoting {
+    /// Number of the latest responders which votes is counted
+    pub window: usize,
+    /// Minimal number of votes for the address to be accepted
+    pub quorum: usize,
+}
+
+impl Default for KVoting {
+    fn default() -> Self {
+        KVoting {
+            window: 64,
+            quorum: 3,
+        }
+    }
+}
+
+/// Discovery of own external address by the addresses which other nodes report
+///
+/// Each responder has one vote, the repeated vote replaces the previous one.
+/// The address is changed when another one gets more votes than the current.
+pub struct KAddrVoter {
+    options: KVoting,
+    votes: HashMap<IpAddr, SocketAddr>,
+    // voters from the oldest to the latest
+    order: VecDeque<IpAddr>,
+    current: Option<SocketAddr>,
+}
+
+impl KAddrVoter {
+    pub fn new(options: KVoting) -> Self {
+        KAddrVoter {
+            options,
+            votes: HashMap::new(),
+            order: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    /// The address which is accepted by voting
+    pub fn current(&self) -> Option<SocketAddr> {
+        self.current
+    }
+
+    /// Count the address reported by given node
+    ///
+    /// Returns new address when it is changed.
+    pub fn vote(&mut self, voter: IpAddr, addr: SocketAddr) -> Option<SocketAddr> {
+        if self.votes.insert(voter, addr).is_some() {
+            self.order.retain(|other| *other != voter);
+        }
+        self.order.push_back(voter);
+        while self.order.len() > self.options.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.votes.remove(&oldest);
+            }
+        }
+
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for addr in self.votes.values() {
+            *counts.entry(*addr).or_insert(0) += 1;
+        }
+        let current_votes = self.current.and_then(|addr| counts.get(&addr).cloned()).unwrap_or(0);
+        let (best, best_votes) = counts.into_iter().max_by_key(|&(_, votes)| votes)?;
+        if Some(best) != self.current && best_votes >= self.options.quorum && best_votes > current_votes {
+            info!("External address is changed to: {}", best);
+            self.current = Some(best);
+            Some(best)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use super::{KVoting, KAddrVoter};
+
+    fn voter(n: u8) -> IpAddr {
+        format!("10.0.0.{}", n).parse().unwrap()
+    }
+
+    #[test]
+    fn test_addr_voter() {
+        let addr1: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let addr2: SocketAddr = "1.2.3.4:7000".parse().unwrap();
+        let mut voting = KAddrVoter::new(KVoting { window: 5, quorum: 2 });
+
+        assert_eq!(voting.vote(voter(1), addr1), None);
+        // the repeated vote isn't counted
+        assert_eq!(voting.vote(voter(1), addr1), None);
+        assert_eq!(voting.vote(voter(2), addr1), Some(addr1));
+        assert_eq!(voting.current(), Some(addr1));
+
+        assert_eq!(voting.vote(voter(3), addr2), None);
+        assert_eq!(voting.vote(voter(4), addr2), None);
+        assert_eq!(voting.vote(voter(5), addr2), Some(addr2));
+
+        // the oldest votes is forgotten
+        assert_eq!(voting.vote(voter(6), addr1), None);
+        assert_eq!(voting.vote(voter(7), addr1), None);
+        assert_eq!(voting.vote(voter(8), addr1), Some(addr1));
+    }
+}
@@ -0,0 +1,25 @@
+use super::KItem;
+
+/// Hook of messages which pass through the service
+///
+/// Each method takes the message and returns it, possibly modified, or `None` to drop it.
+/// The interceptors is called in order of adding, each one gets the result of previous.
+pub trait KInterceptor<Arg, Res> {
+    /// Received query before it is passed to handler
+    fn inbound_query(&mut self, item: KItem<Arg, Res>) -> Option<KItem<Arg, Res>> {
+        Some(item)
+    }
+
+    /// Response or error before it is encoded and sent
+    fn outbound_response(&mut self, item: KItem<Arg, Res>) -> Option<KItem<Arg, Res>> {
+        Some(item)
+    }
+
+    /// Outgoing query before it is sent
+    ///
+    /// The id of item shouldn't be changed because the response is matched by it.
+    /// The call of dropped query fails with `KTransError::Rejected`.
+    fn outgoing_query(&mut self, item: KItem<Arg, Res>) -> Option<KItem<Arg, Res>> {
+        Some(item)
+    }
+}
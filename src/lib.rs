@@ -14,9 +14,13 @@ extern crate log;
 
 extern crate hexdump;
 
+extern crate bytes;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_service;
+extern crate net2;
+#[cfg(feature = "tower")]
+extern crate tower_service;
 
 extern crate rand;
 extern crate crypto_hashes;
@@ -25,12 +29,33 @@ extern crate crypto_hashes;
 pub mod serde_extra;
 
 pub mod rpc;
+pub mod bencode;
 pub mod codec;
 pub mod trans;
+pub mod transport;
+pub mod sim;
+pub mod intercept;
+pub mod observe;
+pub mod metrics;
+pub mod limit;
+pub mod blocklist;
+pub mod external;
 pub mod service;
+pub mod client;
+pub mod dual;
 pub mod dht;
 
-pub use self::rpc::{KAddress, KTransId, KMessage, KError, KErrorKind, KQueryArg};
-pub use self::codec::{KCodec, KItem, KId, KData};
+pub use self::rpc::{KAddress, KTransId, KVersion, KExtra, KMessage, KError, KErrorKind, KQueryArg, KQueryRes};
+pub use self::codec::{KCodec, KQueryNames, KDecodeError, KItem, KId, KData, KMeta, KRaw};
 pub use self::trans::{KTrans};
-pub use self::service::{KTransError, KOptions, KService};
+pub use self::transport::{KTransport, KTimer, KUdpTransport, KMemoryHub, KMemoryTransport};
+pub use self::intercept::{KInterceptor};
+pub use self::observe::{KDirection, KEventKind, KEvent, KObserver};
+pub use self::metrics::{KHistogram, KQueryStats, KStats};
+pub use self::limit::{KOverLimit, KRateLimit, KRateLimiter};
+pub use self::blocklist::{KBlocklist};
+pub use self::external::{KVoting, KAddrVoter};
+pub use self::sim::{KSimOptions, KSimStats, KSimNetwork, KSimTransport};
+pub use self::service::{KTransError, KRetry, KPriority, KOverflow, KOptions, KCallOptions, KService, KRawSink, KRawStream, KAddrStream};
+pub use self::client::{KSyncClient};
+pub use self::dual::{KFamily, KFamilyHandler, KDualService};
@@ -0,0 +1,257 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::cmp::max;
+
+/// What to do with query over the rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KOverLimit {
+    /// Drop query silently
+    #[default]
+    Drop,
+    /// Answer with 202 Server error
+    Reply,
+}
+
+/// Token bucket limits of inbound queries
+///
+/// The query passes when both the bucket of source IP and the bucket of its subnet have a token.
+#[derive(Debug, Clone)]
+pub struct KRateLimit {
+    /// Sustained rate of queries per second from single IP
+    pub ip_rate: f64,
+    /// Number of queries from single IP which may come at once
+    pub ip_burst: f64,
+    /// Sustained rate of queries per second from single subnet
+    pub subnet_rate: f64,
+    /// Number of queries from single subnet which may come at once
+    pub subnet_burst: f64,
+    /// Prefix length of IPv4 subnet
+    pub v4_prefix: u8,
+    /// Prefix length of IPv6 subnet
+    pub v6_prefix: u8,
+    pub over_limit: KOverLimit,
+}
+
+impl Default for KRateLimit {
+    fn default() -> Self {
+        KRateLimit {
+            ip_rate: 10.0,
+            ip_burst: 20.0,
+            subnet_rate: 50.0,
+            subnet_burst: 100.0,
+            v4_prefix: 24,
+            v6_prefix: 64,
+            over_limit: KOverLimit::default(),
+        }
+    }
+}
+
+/// Keep only first `prefix` bits of address
+pub fn mask_ip(ip: &IpAddr, prefix: u8) -> IpAddr {
+    fn mask(octets: &mut [u8], prefix: u8) {
+        for (index, octet) in octets.iter_mut().enumerate() {
+            let bits = (prefix as usize).saturating_sub(index * 8);
+            if bits < 8 {
+                *octet &= !(0xffu8 >> bits);
+            }
+        }
+    }
+    match *ip {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            mask(&mut octets, prefix);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        },
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            mask(&mut octets, prefix);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+struct KBucket {
+    tokens: f64,
+    updated: Instant,
+    // number of queries throttled by this bucket
+    throttled: u64,
+}
+
+impl KBucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        if now > self.updated {
+            self.tokens = (self.tokens + duration_secs(now - self.updated) * rate).min(burst);
+            self.updated = now;
+        }
+    }
+}
+
+type KBuckets = HashMap<IpAddr, KBucket>;
+
+// Get bucket refilled to given time
+fn bucket(buckets: &mut KBuckets, key: IpAddr, rate: f64, burst: f64, now: Instant) -> &mut KBucket {
+    let bucket = buckets.entry(key).or_insert_with(|| KBucket {tokens: burst, updated: now, throttled: 0});
+    bucket.refill(rate, burst, now);
+    bucket
+}
+
+// Drop buckets which is full again, they are the same as new ones unless they keep throttled counter
+fn sweep(buckets: &mut KBuckets, rate: f64, burst: f64, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(rate, burst, now);
+        bucket.tokens < burst || bucket.throttled > 0
+    });
+}
+
+/// Minimal number of tracked sources to start sweeping
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Rate limiter of inbound queries by source IP and subnet
+pub struct KRateLimiter {
+    limit: KRateLimit,
+    ips: KBuckets,
+    subnets: KBuckets,
+    sweep_at: usize,
+}
+
+impl KRateLimiter {
+    pub fn new(limit: KRateLimit) -> Self {
+        KRateLimiter {
+            limit,
+            ips: HashMap::new(),
+            subnets: HashMap::new(),
+            sweep_at: SWEEP_THRESHOLD,
+        }
+    }
+
+    pub fn limit(&self) -> &KRateLimit {
+        &self.limit
+    }
+
+    /// Take token for query from given address
+    ///
+    /// Returns false when query is over the limit.
+    pub fn check(&mut self, ip: &IpAddr, now: Instant) -> bool {
+        if self.ips.len() + self.subnets.len() > self.sweep_at {
+            self.sweep(now);
+        }
+        let limit = &self.limit;
+        let prefix = match *ip {
+            IpAddr::V4(..) => limit.v4_prefix,
+            IpAddr::V6(..) => limit.v6_prefix,
+        };
+        let ip_allowed = bucket(&mut self.ips, *ip, limit.ip_rate, limit.ip_burst, now).tokens >= 1.0;
+        let subnet = bucket(&mut self.subnets, mask_ip(ip, prefix), limit.subnet_rate, limit.subnet_burst, now);
+        if !ip_allowed {
+            self.ips.get_mut(ip).unwrap().throttled += 1;
+            return false;
+        }
+        if subnet.tokens < 1.0 {
+            subnet.throttled += 1;
+            return false;
+        }
+        subnet.tokens -= 1.0;
+        self.ips.get_mut(ip).unwrap().tokens -= 1.0;
+        true
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let limit = &self.limit;
+        sweep(&mut self.ips, limit.ip_rate, limit.ip_burst, now);
+        sweep(&mut self.subnets, limit.subnet_rate, limit.subnet_burst, now);
+        self.sweep_at = max(SWEEP_THRESHOLD, 2 * (self.ips.len() + self.subnets.len()));
+    }
+
+    /// Throttled sources with number of dropped queries
+    ///
+    /// The sources which didn't send anything for a while is forgotten unless they were throttled.
+    pub fn throttled_ips(&self) -> Vec<(IpAddr, u64)> {
+        throttled(&self.ips)
+    }
+
+    /// Throttled subnets with number of dropped queries
+    pub fn throttled_subnets(&self) -> Vec<(IpAddr, u64)> {
+        throttled(&self.subnets)
+    }
+}
+
+fn throttled(buckets: &KBuckets) -> Vec<(IpAddr, u64)> {
+    let mut throttled: Vec<_> = buckets.iter()
+        .filter(|&(_, bucket)| bucket.throttled > 0)
+        .map(|(ip, bucket)| (*ip, bucket.throttled))
+        .collect();
+    throttled.sort();
+    throttled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+    use super::{KRateLimit, KRateLimiter, mask_ip};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_mask_ip() {
+        assert_eq!(mask_ip(&ip("10.1.2.3"), 24), ip("10.1.2.0"));
+        assert_eq!(mask_ip(&ip("10.1.2.3"), 12), ip("10.0.0.0"));
+        assert_eq!(mask_ip(&ip("10.1.2.3"), 32), ip("10.1.2.3"));
+        assert_eq!(mask_ip(&ip("2001:db8:1:2:3:4:5:6"), 64), ip("2001:db8:1:2::"));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = KRateLimiter::new(KRateLimit {
+            ip_rate: 1.0,
+            ip_burst: 2.0,
+            subnet_rate: 2.0,
+            subnet_burst: 3.0,
+            ..KRateLimit::default()
+        });
+        let now = Instant::now();
+
+        assert!(limiter.check(&ip("10.0.0.1"), now));
+        assert!(limiter.check(&ip("10.0.0.1"), now));
+        // ip bucket is empty
+        assert!(!limiter.check(&ip("10.0.0.1"), now));
+        assert!(limiter.check(&ip("10.0.0.2"), now));
+        // subnet bucket is empty
+        assert!(!limiter.check(&ip("10.0.0.3"), now));
+        assert!(limiter.check(&ip("10.0.1.1"), now));
+
+        // both buckets is refilled
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check(&ip("10.0.0.1"), later));
+        assert!(limiter.check(&ip("10.0.0.3"), later));
+
+        assert_eq!(limiter.throttled_ips(), vec![(ip("10.0.0.1"), 1)]);
+        assert_eq!(limiter.throttled_subnets(), vec![(ip("10.0.0.0"), 1)]);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut limiter = KRateLimiter::new(KRateLimit {
+            ip_rate: 1.0,
+            ip_burst: 1.0,
+            ..KRateLimit::default()
+        });
+        let now = Instant::now();
+
+        assert!(limiter.check(&ip("10.0.0.1"), now));
+        assert!(!limiter.check(&ip("10.0.0.1"), now));
+        assert!(limiter.check(&ip("10.0.0.2"), now));
+
+        // the full buckets is dropped, but the throttled counters survive
+        limiter.sweep(now + Duration::from_secs(10));
+        assert_eq!(limiter.ips.len(), 1);
+        assert_eq!(limiter.throttled_ips(), vec![(ip("10.0.0.1"), 1)]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::Write;
+use std::time::Duration;
+use std::collections::BTreeMap;
+
+use super::KErrorKind;
+
+/// Name which the messages of unknown method is counted under
+pub const UNKNOWN_QUERY: &str = "unknown";
+
+/// Upper bounds of latency histogram buckets in milliseconds
+pub const LATENCY_BUCKETS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+/// Histogram of durations with `LATENCY_BUCKETS` bounds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KHistogram {
+    /// Number of samples in each bucket, the last one is over the greatest bound
+    pub buckets: [u64; 12],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl KHistogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let millis = duration_millis(duration);
+        let index = LATENCY_BUCKETS.iter().position(|&bound| millis <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum += duration;
+    }
+}
+
+/// Counters of single query method
+///
+/// The sent and received is from point of view of this node, e.g. the responses
+/// received are responses to own queries and the responses sent are answers to
+/// queries of others.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KQueryStats {
+    pub queries_sent: u64,
+    pub queries_received: u64,
+    pub responses_sent: u64,
+    pub responses_received: u64,
+    pub errors_sent: BTreeMap<KErrorKind, u64>,
+    pub errors_received: BTreeMap<KErrorKind, u64>,
+    /// Own queries which wasn't answered in time
+    pub timeouts: u64,
+    /// Messages of this method which couldn't be decoded
+    pub decode_errors: u64,
+    /// Round-trip time of own queries
+    pub latency: KHistogram,
+}
+
+/// Snapshot of service metrics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KStats {
+    /// Counters by query method name
+    pub queries: BTreeMap<String, KQueryStats>,
+    /// All messages which couldn't be decoded including ones of unknown method
+    pub decode_errors: u64,
+    /// Inbound queries over the rate limit
+    pub throttled: u64,
+    /// Datagrams dropped due to blocklist
+    pub blocked: u64,
+}
+
+impl KStats {
+    /// Counters of given query method, created on first use
+    pub fn query(&mut self, name: &str) -> &mut KQueryStats {
+        if !self.queries.contains_key(name) {
+            self.queries.insert(name.into(), KQueryStats::default());
+        }
+        self.queries.get_mut(name).unwrap()
+    }
+
+    /// Render metrics in Prometheus text format
+    ///
+    /// The metric names start with given prefix, e.g. "krpc".
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+
+        {
+            let mut counter = |name: &str, help: &str, value: &dyn Fn(&KQueryStats) -> u64| {
+                let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+                let _ = writeln!(out, "# TYPE {}_{} counter", prefix, name);
+                for (query, stats) in &self.queries {
+                    let _ = writeln!(out, "{}_{}{{query=\"{}\"}} {}", prefix, name, escape(query), value(stats));
+                }
+            };
+            counter("queries_sent_total", "Queries sent to other nodes.", &|stats| stats.queries_sent);
+            counter("queries_received_total", "Queries received from other nodes.", &|stats| stats.queries_received);
+            counter("responses_sent_total", "Responses sent to other nodes.", &|stats| stats.responses_sent);
+            counter("responses_received_total", "Responses received to own queries.", &|stats| stats.responses_received);
+            counter("timeouts_total", "Own queries which timed out.", &|stats| stats.timeouts);
+            counter("query_decode_errors_total", "Messages which couldn't be decoded by method.", &|stats| stats.decode_errors);
+        }
+
+        {
+            let mut errors = |name: &str, help: &str, value: &dyn Fn(&KQueryStats) -> &BTreeMap<KErrorKind, u64>| {
+                let _ = writeln!(out, "# HELP {}_{} {}", prefix, name, help);
+                let _ = writeln!(out, "# TYPE {}_{} counter", prefix, name);
+                for (query, stats) in &self.queries {
+                    for (kind, count) in value(stats) {
+                        let _ = writeln!(out, "{}_{}{{query=\"{}\",kind=\"{}\"}} {}", prefix, name, escape(query),
+                                         error_label(kind), count);
+                    }
+                }
+            };
+            errors("errors_sent_total", "KRPC errors sent to other nodes.", &|stats| &stats.errors_sent);
+            errors("errors_received_total", "KRPC errors received to own queries.", &|stats| &stats.errors_received);
+        }
+
+        let _ = writeln!(out, "# HELP {}_decode_errors_total Messages which couldn't be decoded.", prefix);
+        let _ = writeln!(out, "# TYPE {}_decode_errors_total counter", prefix);
+        let _ = writeln!(out, "{}_decode_errors_total {}", prefix, self.decode_errors);
+
+        let _ = writeln!(out, "# HELP {}_throttled_total Inbound queries over the rate limit.", prefix);
+        let _ = writeln!(out, "# TYPE {}_throttled_total counter", prefix);
+        let _ = writeln!(out, "{}_throttled_total {}", prefix, self.throttled);
+
+        let _ = writeln!(out, "# HELP {}_blocked_total Datagrams dropped due to blocklist.", prefix);
+        let _ = writeln!(out, "# TYPE {}_blocked_total counter", prefix);
+        let _ = writeln!(out, "{}_blocked_total {}", prefix, self.blocked);
+
+        let _ = writeln!(out, "# HELP {}_latency_seconds Round-trip time of own queries.", prefix);
+        let _ = writeln!(out, "# TYPE {}_latency_seconds histogram", prefix);
+        for (query, stats) in &self.queries {
+            let query = escape(query);
+            let latency = &stats.latency;
+            let mut count = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(latency.buckets.iter()) {
+                count += *bucket;
+                let _ = writeln!(out, "{}_latency_seconds_bucket{{query=\"{}\",le=\"{}\"}} {}", prefix, query,
+                                 *bound as f64 / 1000.0, count);
+            }
+            let _ = writeln!(out, "{}_latency_seconds_bucket{{query=\"{}\",le=\"+Inf\"}} {}", prefix, query, latency.count);
+            let _ = writeln!(out, "{}_latency_seconds_sum{{query=\"{}\"}} {}", prefix, query, duration_secs(latency.sum));
+            let _ = writeln!(out, "{}_latency_seconds_count{{query=\"{}\"}} {}", prefix, query, latency.count);
+        }
+
+        out
+    }
+}
+
+// Name of known error kind or code of unknown one
+fn error_label(kind: &KErrorKind) -> String {
+    match *kind {
+        KErrorKind::Other(code) => code.to_string(),
+        kind => format!("{:?}", kind).to_lowercase(),
+    }
+}
+
+// Escape label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{KStats, KErrorKind};
+
+    #[test]
+    fn test_prometheus() {
+        let mut stats = KStats::default();
+        stats.decode_errors = 2;
+        {
+            let ping = stats.query("ping");
+            ping.queries_sent = 3;
+            ping.responses_received = 2;
+            *ping.errors_received.entry(KErrorKind::Method).or_insert(0) += 1;
+            *ping.errors_received.entry(KErrorKind::Other(299)).or_insert(0) += 1;
+            ping.latency.observe(Duration::from_millis(3));
+            ping.latency.observe(Duration::from_millis(40));
+            ping.latency.observe(Duration::from_secs(20));
+        }
+
+        let text = stats.to_prometheus("krpc");
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE krpc_queries_sent_total counter"));
+        assert!(lines.contains(&"krpc_queries_sent_total{query=\"ping\"} 3"));
+        assert!(lines.contains(&"krpc_responses_received_total{query=\"ping\"} 2"));
+        assert!(lines.contains(&"krpc_errors_received_total{query=\"ping\",kind=\"method\"} 1"));
+        assert!(lines.contains(&"krpc_errors_received_total{query=\"ping\",kind=\"299\"} 1"));
+        assert!(lines.contains(&"krpc_decode_errors_total 2"));
+        assert!(lines.contains(&"krpc_latency_seconds_bucket{query=\"ping\",le=\"0.005\"} 1"));
+        assert!(lines.contains(&"krpc_latency_seconds_bucket{query=\"ping\",le=\"0.05\"} 2"));
+        assert!(lines.contains(&"krpc_latency_seconds_bucket{query=\"ping\",le=\"10\"} 2"));
+        assert!(lines.contains(&"krpc_latency_seconds_bucket{query=\"ping\",le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"krpc_latency_seconds_sum{query=\"ping\"} 20.043"));
+        assert!(lines.contains(&"krpc_latency_seconds_count{query=\"ping\"} 3"));
+    }
+}
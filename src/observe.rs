@@ -0,0 +1,137 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use futures::{Stream, Poll, Async};
+use futures::task::{self, Task};
+
+use super::{KTransId, KErrorKind};
+
+/// Direction of observed message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KEventKind {
+    Query,
+    Response,
+    Error(KErrorKind),
+}
+
+/// Message which passed through the service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KEvent {
+    pub direction: KDirection,
+    /// Source address of inbound or destination address of outbound message
+    pub peer: SocketAddr,
+    pub tid: Option<KTransId>,
+    pub kind: KEventKind,
+    /// Query method name
+    ///
+    /// For responses and errors it's known only when they match own transaction.
+    pub query: Option<String>,
+    /// Round-trip time of own transaction, set for inbound responses and errors
+    pub rtt: Option<Duration>,
+}
+
+struct KObserverState {
+    events: VecDeque<KEvent>,
+    capacity: usize,
+    lost: u64,
+    task: Option<Task>,
+    closed: bool,
+}
+
+/// Stream of messages which passed through the service
+///
+/// The stream is lossy: when it isn't read fast enough the oldest events is dropped.
+/// It ends when the service is stopped.
+pub struct KObserver(Rc<RefCell<KObserverState>>);
+
+impl KObserver {
+    /// Number of events dropped due to overflow
+    pub fn lost(&self) -> u64 {
+        self.0.borrow().lost
+    }
+}
+
+impl Stream for KObserver {
+    type Item = KEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<KEvent>, ()> {
+        let mut state = self.0.borrow_mut();
+        if let Some(event) = state.events.pop_front() {
+            return Ok(Async::Ready(Some(event)));
+        }
+        if state.closed {
+            return Ok(Async::Ready(None));
+        }
+        state.task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Server side of observer
+pub(crate) struct KObserverSink(Rc<RefCell<KObserverState>>);
+
+pub(crate) fn observer(capacity: usize) -> (KObserver, KObserverSink) {
+    let state = Rc::new(RefCell::new(KObserverState {
+        events: VecDeque::new(),
+        capacity,
+        lost: 0,
+        task: None,
+        closed: false,
+    }));
+    (KObserver(state.clone()), KObserverSink(state))
+}
+
+/// Broadcast of events to observers
+pub(crate) struct KObservers(Vec<KObserverSink>);
+
+impl KObservers {
+    pub fn new() -> Self {
+        KObservers(Vec::new())
+    }
+
+    pub fn add(&mut self, sink: KObserverSink) {
+        self.0.push(sink);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn publish(&mut self, event: KEvent) {
+        // the observer is dropped when server holds the last reference
+        self.0.retain(|sink| Rc::strong_count(&sink.0) > 1);
+        for sink in &self.0 {
+            let mut state = sink.0.borrow_mut();
+            if state.events.len() >= state.capacity {
+                state.events.pop_front();
+                state.lost += 1;
+            }
+            state.events.push_back(event.clone());
+            if let Some(task) = state.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+impl Drop for KObservers {
+    fn drop(&mut self) {
+        for sink in &self.0 {
+            let mut state = sink.0.borrow_mut();
+            state.closed = true;
+            if let Some(task) = state.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
@@ -1,6 +1,16 @@
+use std::fmt;
+use std::borrow::Cow;
 use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use bytes::Bytes;
 use serde_bytes;
-use serde_extra::socket_addr;
+use serde_bytes::ByteBuf;
+use serde_bencode;
+use serde_bencode::value::Value;
+use serde_bencode::ser::to_bytes;
+use serde::ser::Serialize;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_extra::{socket_addr, option_bool};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct KAddress (
@@ -8,10 +18,10 @@ pub struct KAddress (
     pub SocketAddr,
 );
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Transaction id which shares the buffer of received message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct KTransId (
-    #[serde(with = "serde_bytes")]
-    pub Vec<u8>,
+    pub Bytes,
 );
 
 impl<'a> From<&'a str> for KTransId {
@@ -32,6 +42,28 @@ impl AsRef<[u8]> for KTransId {
     }
 }
 
+/// Client version string (the "v" key of message)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KVersion (
+    #[serde(with = "serde_bytes")]
+    pub Vec<u8>,
+);
+
+impl<'a> From<&'a str> for KVersion {
+    fn from(s: &'a str) -> Self {
+        KVersion(s.into())
+    }
+}
+
+impl<'a> From<&'a [u8]> for KVersion {
+    fn from(b: &'a [u8]) -> Self {
+        KVersion(b.into())
+    }
+}
+
+/// Unknown keys of dictionary which is kept to be encoded back
+pub type KExtra = BTreeMap<ByteBuf, Value>;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "y")]
 pub enum KMessage<Query, Arg, Res> {
@@ -43,6 +75,11 @@ pub enum KMessage<Query, Arg, Res> {
         query: Query,
         #[serde(rename = "a")]
         arg: Arg,
+        v: Option<KVersion>,
+        #[serde(default, with = "option_bool")]
+        ro: bool,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     #[serde(rename = "r")]
     Response {
@@ -51,6 +88,9 @@ pub enum KMessage<Query, Arg, Res> {
         tid: Option<KTransId>,
         #[serde(rename = "r")]
         res: Res,
+        v: Option<KVersion>,
+        #[serde(flatten)]
+        extra: KExtra,
     },
     #[serde(rename = "e")]
     Error {
@@ -59,15 +99,82 @@ pub enum KMessage<Query, Arg, Res> {
         tid: Option<KTransId>,
         #[serde(rename = "e")]
         error: KError,
+        v: Option<KVersion>,
+        #[serde(flatten)]
+        extra: KExtra,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Error reply with code and message
+///
+/// The message is kept as is, because it isn't always valid UTF-8.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct KError(
     pub KErrorKind,
-    pub String,
+    #[serde(with = "serde_bytes")]
+    pub Vec<u8>,
 );
 
+impl KError {
+    /// The message with invalid UTF-8 sequences replaced
+    pub fn message(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.1)
+    }
+}
+
+// The error is decoded leniently: the code or message may be missing and
+// the extra items is ignored, the single code or message is accepted as well.
+impl<'de> Deserialize<'de> for KError {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = KError;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("list of error code and message")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<KError, A::Error>
+                where A: de::SeqAccess<'de>
+            {
+                let mut kind = None;
+                let mut message = None;
+                while let Some(item) = seq.next_element::<Value>()? {
+                    match item {
+                        Value::Int(code) if kind.is_none() => kind = Some(KErrorKind::from(code)),
+                        Value::Bytes(bytes) if message.is_none() => message = Some(bytes),
+                        _ => (),
+                    }
+                }
+                Ok(KError(kind.unwrap_or(KErrorKind::Generic), message.unwrap_or_default()))
+            }
+
+            fn visit_i64<E>(self, code: i64) -> Result<KError, E>
+                where E: de::Error
+            {
+                Ok(KError(KErrorKind::from(code), Vec::new()))
+            }
+
+            fn visit_bytes<E>(self, message: &[u8]) -> Result<KError, E>
+                where E: de::Error
+            {
+                Ok(KError(KErrorKind::Generic, message.into()))
+            }
+
+            fn visit_str<E>(self, message: &str) -> Result<KError, E>
+                where E: de::Error
+            {
+                self.visit_bytes(message.as_bytes())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
 serde_numeric_enum!(KErrorKind {
     Generic = 201,
     Server = 202,
@@ -75,8 +182,34 @@ serde_numeric_enum!(KErrorKind {
     Method = 204,
 });
 
-pub trait KQueryArg {
+/// Query argument which schema depends on the method
+pub trait KQueryArg: Sized {
     type Query;
     
     fn query(&self) -> Self::Query;
+
+    /// Decode the "a" dictionary of query with given method
+    ///
+    /// The byte strings may be sliced from the buffer without copying.
+    fn decode(query: &Self::Query, buf: &Bytes) -> Result<Self, serde_bencode::Error>;
+
+    /// Append the bencoded argument to buffer
+    fn encode(&self, buf: &mut Vec<u8>) where Self: Serialize {
+        buf.extend(to_bytes(self).unwrap());
+    }
+}
+
+/// Response which schema depends on the query
+pub trait KQueryRes: Sized {
+    type Query;
+
+    /// Decode the "r" dictionary of response to given query
+    ///
+    /// The byte strings may be sliced from the buffer without copying.
+    fn decode(query: &Self::Query, buf: &Bytes) -> Result<Self, serde_bencode::Error>;
+
+    /// Append the bencoded response to buffer
+    fn encode(&self, buf: &mut Vec<u8>) where Self: Serialize {
+        buf.extend(to_bytes(self).unwrap());
+    }
 }
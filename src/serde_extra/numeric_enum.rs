@@ -1,16 +1,38 @@
+/// Enum which is encoded as integer
+///
+/// The unknown values is kept in the `Other` variant, so decoding never fails on them.
 #[macro_export]
 macro_rules! serde_numeric_enum {
     ($name:ident { $($variant:ident = $value:expr, )* }) => {
-        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
         pub enum $name {
-            $($variant = $value,)*
+            $($variant,)*
+            Other(i64),
+        }
+
+        impl $name {
+            pub fn code(&self) -> i64 {
+                match *self {
+                    $( $name::$variant => $value, )*
+                    $name::Other(value) => value,
+                }
+            }
+        }
+
+        impl From<i64> for $name {
+            fn from(value: i64) -> Self {
+                match value {
+                    $( $value => $name::$variant, )*
+                    _ => $name::Other(value),
+                }
+            }
         }
 
         impl ::serde::Serialize for $name {
             fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
                 where S: ::serde::Serializer
             {
-                serializer.serialize_i64(*self as i64)
+                serializer.serialize_i64(self.code())
             }
         }
 
@@ -32,12 +54,16 @@ macro_rules! serde_numeric_enum {
                     fn visit_i64<E>(self, value: i64) -> Result<$name, E>
                         where E: ::serde::de::Error
                     {
-                        match value {
-                            $( $value => Ok($name::$variant), )*
-                            _ => Err(E::custom(
-                                format!("unknown {} value: {}",
-                                stringify!($name), value))),
+                        Ok($name::from(value))
+                    }
+
+                    fn visit_u64<E>(self, value: u64) -> Result<$name, E>
+                        where E: ::serde::de::Error
+                    {
+                        if value > i64::MAX as u64 {
+                            return Err(E::invalid_value(::serde::de::Unexpected::Unsigned(value), &self));
                         }
+                        Ok($name::from(value as i64))
                     }
                 }
                 
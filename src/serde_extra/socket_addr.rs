@@ -1,4 +1,4 @@
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 
 use serde_bytes;
 use serde::ser::Serializer;
@@ -12,8 +12,11 @@ pub fn to_bytes(buf: &mut Vec<u8>, addr: &SocketAddr) {
             buf.push((port >> 8) as u8);
             buf.push((port & 0xff) as u8);
         },
-        &SocketAddr::V6(_v6) => {
-            // not implemented
+        &SocketAddr::V6(v6) => {
+            buf.extend(&v6.ip().octets());
+            let port = v6.port();
+            buf.push((port >> 8) as u8);
+            buf.push((port & 0xff) as u8);
         },
     };
 }
@@ -25,6 +28,13 @@ pub fn from_bytes(buf: &[u8]) -> Result<SocketAddr, ()> {
             let port = ((buf[4] as u16) << 8) | (buf[5] as u16);
             Ok(SocketAddr::new(addr, port))
         },
+        18 => {
+            let mut octets = [0u8; 16];
+            octets.clone_from_slice(&buf[..16]);
+            let addr = IpAddr::V6(Ipv6Addr::from(octets));
+            let port = ((buf[16] as u16) << 8) | (buf[17] as u16);
+            Ok(SocketAddr::new(addr, port))
+        },
         _ => {
             Err(())
         }
@@ -1,158 +1,1165 @@
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{self, IpAddr, SocketAddr};
+use std::cmp::{self, Ordering};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{BinaryHeap, VecDeque};
 
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
-use futures::{Future, Sink, Stream};
+use bytes::Bytes;
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink};
 use futures::future::{Either, Loop, loop_fn, ok, err};
+use futures::stream::FuturesUnordered;
+use futures::task::Task;
 use futures::unsync::{oneshot, mpsc};
 
-use tokio_core::reactor::{Handle, Timeout};
-use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+use tokio_core::net::UdpCodec;
 use tokio_service::Service;
+#[cfg(feature = "tower")]
+use tower_service;
+#[cfg(feature = "tower")]
+use futures::task;
 
-use super::{KError, KQueryArg, KCodec, KItem, KData, KTrans, KId};
+use super::{KError, KErrorKind, KVersion, KQueryArg, KQueryRes, KCodec, KItem, KData, KMeta, KRaw, KTrans, KId, KSyncClient};
+use super::codec::{is_krpc, peek_header, KQueryNames, KDecodeError};
+use super::transport::{KTransport, KTimer, KUdpTransport};
+use super::intercept::KInterceptor;
+use super::metrics::{KStats, UNKNOWN_QUERY};
+use super::limit::{KRateLimit, KRateLimiter, KOverLimit};
+use super::blocklist::KBlocklist;
+use super::external::{KVoting, KAddrVoter};
+use super::observe::{self, KDirection, KEventKind, KEvent, KObserver, KObserverSink, KObservers};
+use super::trans::DEFAULT_TID_LEN;
 
 #[derive(Debug)]
 pub enum KTransError {
     KError(KError),
     IOError(Error),
     Timeout,
+    /// Transaction pool is full or all transaction ids with the node are in use
+    Overflow,
+    /// Service is shut down
+    Shutdown,
+    /// Query is dropped by interceptor
+    Rejected,
+    /// Destination address is in blocklist
+    Blocked,
+    /// Response doesn't match the query
+    BadResponse(String),
 }
 
 type KTransResponder<Res> = oneshot::Sender<Result<Res, KTransError>>;
-type KTransIdenter = oneshot::Sender<KId>;
-struct KTransQuery<Arg, Res>(SocketAddr, Arg, KTransResponder<Res>, KTransIdenter);
+type KTransIdenter = oneshot::Sender<Result<KId, KTransError>>;
+
+/// Outstanding transaction
+struct KPending<Query, Res> {
+    res_tx: KTransResponder<Res>,
+    query: Query,
+    sent: Instant,
+}
+struct KTransQuery<Arg, Res>(SocketAddr, Arg, KMeta, KPriority, KTransResponder<Res>, KTransIdenter);
+type KQuerySender<Arg, Res> = mpsc::Sender<KTransQuery<Arg, Res>>;
+type KQueryReceiver<Arg, Res> = mpsc::Receiver<KTransQuery<Arg, Res>>;
+
+enum KControl<Arg, Res> {
+    /// Cancel timed out transaction
+    Cancel(KId),
+    /// Start forwarding of foreign datagrams
+    Demux(mpsc::UnboundedSender<KRaw>, KRawReceiver),
+    /// Add interceptor on top of stack
+    Intercept(Box<dyn KInterceptor<Arg, Res>>),
+    /// Start notifying about external address changes
+    WatchAddr(mpsc::UnboundedSender<SocketAddr>),
+    /// Start broadcasting of messages
+    Observe(KObserverSink),
+    Shutdown,
+}
+
+/// Sink of datagrams to send through the service transport
+pub type KRawSink = mpsc::UnboundedSender<KRaw>;
+/// Stream of received datagrams which isn't KRPC messages
+pub type KRawStream = mpsc::UnboundedReceiver<KRaw>;
+type KRawReceiver = mpsc::UnboundedReceiver<KRaw>;
+/// Stream of changes of external address
+pub type KAddrStream = mpsc::UnboundedReceiver<SocketAddr>;
+
+type KControlSender<Arg, Res> = mpsc::UnboundedSender<KControl<Arg, Res>>;
+type KControlReceiver<Arg, Res> = mpsc::UnboundedReceiver<KControl<Arg, Res>>;
+
+/// Length of server queue shared with service handles
+#[derive(Default)]
+struct KQueueState {
+    len: Cell<usize>,
+    // the handles waiting for free place in queue
+    blocked: RefCell<Vec<Task>>,
+}
+
+impl KQueueState {
+    fn set_len(&self, len: usize, max: usize) {
+        self.len.set(len);
+        if len < max {
+            for task in self.blocked.borrow_mut().drain(..) {
+                task.notify();
+            }
+        }
+    }
+
+    // Wait for free place in queue, the task is notified once however many times it polls
+    #[cfg(feature = "tower")]
+    fn block(&self) {
+        let mut blocked = self.blocked.borrow_mut();
+        if !blocked.iter().any(Task::will_notify_current) {
+            blocked.push(task::current());
+        }
+    }
+}
+
+/// Retry policy for outgoing queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KRetry {
+    /// Send query only once
+    #[default]
+    Never,
+    /// Resend query up to given number of times when it times out
+    Fixed(usize),
+    /// Like fixed, but the timeout is doubled with each attempt
+    Backoff(usize),
+}
+
+impl KRetry {
+    fn retries(&self) -> usize {
+        match *self {
+            KRetry::Never => 0,
+            KRetry::Fixed(n) | KRetry::Backoff(n) => n,
+        }
+    }
+
+    fn timeout(&self, base: Duration, attempt: usize) -> Duration {
+        match *self {
+            KRetry::Backoff(..) => {
+                let max = cmp::max(base, Duration::from_secs(MAX_BACKOFF_TIMEOUT));
+                let factor = if attempt < 32 { 1u32 << attempt } else { u32::MAX };
+                base.checked_mul(factor).map_or(max, |timeout| cmp::min(timeout, max))
+            },
+            _ => base,
+        }
+    }
+}
+
+/// Limit of timeout which grows with backoff retries in seconds
+const MAX_BACKOFF_TIMEOUT: u64 = 300;
+
+/// Order in which queued outgoing queries are sent
+///
+/// The priority matters only for the queries which wait to be sent,
+/// i.e. when transaction pool is full or queries are made faster than they're sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum KPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// What to do with outgoing query when transaction pool is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KOverflow {
+    /// Keep query queued until some transaction ends
+    ///
+    /// The timeout of call starts when query is sent, so the time in queue isn't counted.
+    #[default]
+    Wait,
+    /// Fail query with `KTransError::Overflow`
+    Fail,
+    /// Fail the oldest outstanding transaction with `KTransError::Overflow` to free the place
+    DropOldest,
+}
 
 #[derive(Debug, Clone)]
 pub struct KOptions {
+    /// Time to wait for response since query is sent
     pub timeout: Duration,
+    pub retry: KRetry,
+    /// Length of generated transaction ids in bytes, cannot be zero
+    pub tid_len: usize,
+    /// Maximum number of outstanding transactions
+    pub max_active: usize,
+    /// Maximum number of outgoing queries waiting to be sent
+    ///
+    /// When this limit is reached the calls will wait until queue has free place
+    /// and the service handle isn't ready.
+    pub max_queued: usize,
+    /// Transaction pool overflow policy
+    pub overflow: KOverflow,
+    /// Limits of inbound queries, unlimited by default
+    pub rate_limit: Option<KRateLimit>,
+    /// The addresses to not receive from and send to
+    ///
+    /// The list can be updated at runtime through its clones.
+    pub blocklist: Option<KBlocklist>,
+    /// External address discovery by the "ip" key of responses
+    pub voting: KVoting,
+}
+
+impl Default for KOptions {
+    fn default() -> Self {
+        KOptions {
+            timeout: Duration::from_secs(2),
+            retry: KRetry::default(),
+            tid_len: DEFAULT_TID_LEN,
+            max_active: 1024,
+            max_queued: 64,
+            overflow: KOverflow::default(),
+            rate_limit: None,
+            blocklist: None,
+            voting: KVoting::default(),
+        }
+    }
+}
+
+/// Options of single outgoing call
+///
+/// The unset fields falls back to service-wide `KOptions`.
+#[derive(Debug, Clone, Default)]
+pub struct KCallOptions {
+    pub timeout: Option<Duration>,
+    pub retry: Option<KRetry>,
+    pub priority: KPriority,
+    /// Client version to send with query
+    pub version: Option<KVersion>,
+    /// Mark query as sent by read-only node (BEP-43)
+    pub read_only: bool,
 }
 
-#[derive(Clone)]
 pub struct KService<Query, Arg, Res, Handler> {
     options: KOptions,
-    query_tx: mpsc::Sender<Either<KTransQuery<Arg, Res>, KId>>,
+    query_tx: KQuerySender<Arg, Res>,
+    control_tx: KControlSender<Arg, Res>,
+    active: Rc<Cell<usize>>,
+    queue: Rc<KQueueState>,
+    stats: Rc<RefCell<KStats>>,
+    limiter: Option<Rc<RefCell<KRateLimiter>>>,
+    external_addr: Rc<Cell<Option<SocketAddr>>>,
+    local_addr: SocketAddr,
     handle: Handle,
+    timer: Rc<dyn KTimer>,
     phantom: PhantomData<(Query, Handler)>,
 }
 
+// The handle can be cloned regardless of handler type
+impl<Query, Arg, Res, Handler> Clone for KService<Query, Arg, Res, Handler> {
+    fn clone(&self) -> Self {
+        KService {
+            options: self.options.clone(),
+            query_tx: self.query_tx.clone(),
+            control_tx: self.control_tx.clone(),
+            active: self.active.clone(),
+            queue: self.queue.clone(),
+            stats: self.stats.clone(),
+            limiter: self.limiter.clone(),
+            external_addr: self.external_addr.clone(),
+            local_addr: self.local_addr,
+            handle: self.handle.clone(),
+            timer: self.timer.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 impl<'s, Query, Arg, Res, Handler> KService<Query, Arg, Res, Handler>
     where Query: 's + Serialize + DeserializeOwned + Debug + Eq,
           Arg: 's + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
-          Res: 's + Serialize + DeserializeOwned + Debug,
+          Res: 's + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
           Handler: 's + Service<Request = Arg, Response = Res, Error = KError>,
 {
+    /// Create service bound to the given address
+    ///
+    /// Panics when socket cannot be bound, use `bind` to handle this case.
     pub fn new(handler: Handler, addr: &SocketAddr, handle: &Handle, options: KOptions) -> (Self, impl Future<Item = (), Error = Error> + 's) {
-        let trans: KTrans<KTransResponder<Res>> = KTrans::new();
+        KService::bind(handler, addr, handle, options).unwrap()
+    }
+
+    /// Create service bound to the given address
+    pub fn bind(handler: Handler, addr: &SocketAddr, handle: &Handle, options: KOptions) -> Result<(Self, impl Future<Item = (), Error = Error> + 's), Error> {
+        let transport = KUdpTransport::bind(addr, handle)?;
+        KService::with_transport(handler, transport, handle, options)
+    }
+
+    /// Create service which uses already bound socket
+    pub fn from_socket(handler: Handler, socket: net::UdpSocket, handle: &Handle, options: KOptions) -> Result<(Self, impl Future<Item = (), Error = Error> + 's), Error> {
+        let transport = KUdpTransport::from_socket(socket, handle)?;
+        KService::with_transport(handler, transport, handle, options)
+    }
+
+    /// Create service which runs over the given transport
+    pub fn with_transport<Transport>(handler: Handler, transport: Transport, handle: &Handle, options: KOptions) -> Result<(Self, impl Future<Item = (), Error = Error> + 's), Error>
+        where Transport: 's + KTransport
+    {
+        if options.tid_len == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Transaction id cannot be empty"));
+        }
+        let trans: KTrans<KPending<Query, Res>> = KTrans::with_tid_len(options.tid_len)
+            .with_limit(options.max_active);
         let codec: KCodec<Query, Arg, Res> = KCodec::new();
-        let socket = UdpSocket::bind(addr, handle).unwrap();
+        let local_addr = transport.local_addr()?;
+        let timer = transport.timer(handle);
         let handle = handle.clone();
-        
-        info!("Listening on: {}", socket.local_addr().unwrap());
-        
-        let (net_tx, net_rx) = socket.framed(codec).split();
+
+        info!("Listening on: {}", local_addr);
+
         let (query_tx, query_rx) = mpsc::channel(1);
-        // Compose event stream
-        let event_rx = net_rx.map(Either::A)
-            .select(query_rx.map(Either::B)
-                    .map_err(|_| Error::new(ErrorKind::Other, "Query error")))
-            .into_future();
-        (KService { options, query_tx, handle, phantom: PhantomData },
-         loop_fn((event_rx, net_tx, trans, handler),
-                 |(event_rx, net_tx, mut trans, handler)| {
-                     event_rx.map_err(|(err, ..)| {
-                         error!("recv err: {}", err);
-                         err
-                     }).and_then(|(item, event_stream)| {
-                         if let Some(item) = item {
-                             let event_rx = event_stream.into_future();
-                             match item {
-                                 Either::A(KItem(trans_id, msg)) => {
-                                     match msg {
-                                         KData::Query(arg) => {
-                                             return Either::B(Either::A(handler.call(arg).then(|result| {
-                                                 let resp = match result {
-                                                     Ok(res) => KData::Response(res),
-                                                     Err(err) => KData::Error(err),
-                                                 };
-                                                 net_tx.send(KItem(trans_id, resp))
-                                                     .and_then(|net_tx| {
-                                                         ok(Loop::Continue((event_rx, net_tx, trans, handler)))
-                                                     })
-                                             })));
-                                         },
-                                         KData::Response(res) => {
-                                             if let Some(res_tx) = trans.end(&trans_id) {
-                                                 let _ = res_tx.send(Ok(res));
-                                             }
-                                         },
-                                         KData::Error(err) => {
-                                             warn!("Received KRPC error: {:?}", err);
-                                             if let Some(res_tx) = trans.end(&trans_id) {
-                                                 let _ = res_tx.send(Err(KTransError::KError(err)));
-                                             }
-                                         },
-                                     }
-                                 },
-                                 Either::B(Either::A(KTransQuery(addr, arg, res_tx, tid_tx))) => {
-                                     let trans_id = trans.start(addr, res_tx);
-                                     let _ = tid_tx.send(trans_id.clone());
-                                     return Either::B(Either::B(
-                                         net_tx.send(KItem(trans_id, KData::Query(arg)))
-                                             .and_then(|net_tx| {
-                                                 ok(Loop::Continue((event_rx, net_tx, trans, handler)))
-                                             })))
-                                 },
-                                 Either::B(Either::B(trans_id)) => {
-                                     warn!("DHT Response timeout");
-                                     if let Some(res_tx) = trans.end(&trans_id) {
-                                         let _ = res_tx.send(Err(KTransError::Timeout));
-                                     }
-                                 },
-                             }
-                             Either::A(ok(Loop::Continue((event_rx, net_tx, trans, handler))))
-                         } else {
-                             Either::A(ok(Loop::Break(())))
-                         }
-                     })
-                 }
-         ))
-    }
-
-    // At the moment some objective difficulties didn't allow implement tokio_service::Service trait directly.
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let active = Rc::new(Cell::new(0));
+        let queue = Rc::new(KQueueState::default());
+        let stats = Rc::new(RefCell::new(KStats::default()));
+        let limiter = options.rate_limit.clone().map(|limit| Rc::new(RefCell::new(KRateLimiter::new(limit))));
+        let voter = KAddrVoter::new(options.voting.clone());
+        let external_addr = Rc::new(Cell::new(None));
+        Ok((KService { options: options.clone(), query_tx, control_tx, active: active.clone(), queue: queue.clone(), stats: stats.clone(), limiter: limiter.clone(), external_addr: external_addr.clone(), local_addr, handle, timer, phantom: PhantomData },
+         KServer {
+             options,
+             transport,
+             codec,
+             names: KQueryNames::default(),
+             query_rx: Some(query_rx),
+             control_rx,
+             closing: false,
+             raw_tx: None,
+             raw_rx: None,
+             trans,
+             active,
+             queue,
+             stats,
+             limiter,
+             voter,
+             external_addr,
+             addr_watchers: Vec::new(),
+             handler,
+             interceptors: Vec::new(),
+             observers: KObservers::new(),
+             queued: BinaryHeap::new(),
+             queued_seq: 0,
+             replies: FuturesUnordered::new(),
+             outgoing: VecDeque::new(),
+         }))
+    }
+
+    /// The address which service is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of outstanding transactions
+    pub fn active(&self) -> usize {
+        self.active.get()
+    }
+
+    /// Snapshot of metrics
+    pub fn stats(&self) -> KStats {
+        self.stats.borrow().clone()
+    }
+
+    /// Own address as seen by other nodes
+    ///
+    /// It's known when enough nodes responded with the same address.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.external_addr.get()
+    }
+
+    /// Subscribe to changes of external address
+    pub fn external_addr_changes(&self) -> KAddrStream {
+        let (addr_tx, addr_rx) = mpsc::unbounded();
+        let _ = self.control_tx.unbounded_send(KControl::WatchAddr(addr_tx));
+        addr_rx
+    }
+
+    /// Source addresses which queries was throttled with number of dropped queries
+    pub fn throttled_ips(&self) -> Vec<(IpAddr, u64)> {
+        self.limiter.as_ref().map(|limiter| limiter.borrow().throttled_ips()).unwrap_or_default()
+    }
+
+    /// Source subnets which queries was throttled with number of dropped queries
+    pub fn throttled_subnets(&self) -> Vec<(IpAddr, u64)> {
+        self.limiter.as_ref().map(|limiter| limiter.borrow().throttled_subnets()).unwrap_or_default()
+    }
+
+    /// Share service transport with another protocol
+    ///
+    /// Returns the sink to send datagrams through the transport and the stream of received datagrams
+    /// which doesn't look like KRPC messages. Without it such datagrams is dropped.
+    /// The new call replaces the previously returned pair.
+    pub fn demux(&self) -> (KRawSink, KRawStream) {
+        let (in_tx, in_rx) = mpsc::unbounded();
+        let (out_tx, out_rx) = mpsc::unbounded();
+        let _ = self.control_tx.unbounded_send(KControl::Demux(in_tx, out_rx));
+        (out_tx, in_rx)
+    }
+
+    /// Add interceptor of messages
+    ///
+    /// The interceptor is applied to the messages which is processed after this call.
+    pub fn intercept<Interceptor>(&self, interceptor: Interceptor)
+        where Interceptor: 'static + KInterceptor<Arg, Res>
+    {
+        let _ = self.control_tx.unbounded_send(KControl::Intercept(Box::new(interceptor)));
+    }
+
+    /// Subscribe to messages which pass through the service
+    ///
+    /// The observer buffers up to given number of events and drops the oldest ones on overflow.
+    /// The inbound messages is observed as they are received, before interceptors,
+    /// and the outbound ones as they are sent, after interceptors.
+    pub fn observe(&self, capacity: usize) -> KObserver {
+        let (observer, sink) = observe::observer(capacity);
+        let _ = self.control_tx.unbounded_send(KControl::Observe(sink));
+        observer
+    }
+
+    /// Stop the service
+    ///
+    /// The new calls will be rejected and the pending calls will fail with `KTransError::Shutdown`.
+    /// The server future resolves when the responses to already received queries is sent.
+    pub fn shutdown(&self) {
+        let _ = self.control_tx.unbounded_send(KControl::Shutdown);
+    }
+
     pub fn call(&self, addr: SocketAddr, arg: Arg) -> impl Future<Item = Res, Error = KTransError> {
-        let KOptions {timeout, ..} = self.options;
-        let (res_tx, res_rx) = oneshot::channel();
-        let (tid_tx, tid_rx) = oneshot::channel();
-        let handle = self.handle.clone();
-        let cancel_tx = self.query_tx.clone();
+        self.call_with(addr, arg, KCallOptions::default())
+    }
+
+    /// Make outgoing query using specific options
+    ///
+    /// The argument is encoded once when the query may be retried and each retry decodes its own copy.
+    pub fn call_with(&self, addr: SocketAddr, arg: Arg, options: KCallOptions) -> impl Future<Item = Res, Error = KTransError> {
+        let KCallOptions {timeout, retry, priority, version, read_only} = options;
+        let timeout = timeout.unwrap_or(self.options.timeout);
+        let retry = retry.unwrap_or(self.options.retry);
+        let meta = KMeta {version, read_only, ..KMeta::default()};
+        let timer = self.timer.clone();
         let query_tx = self.query_tx.clone();
+        let control_tx = self.control_tx.clone();
+
+        let query = arg.query();
+        let mut encoded = Vec::new();
+        if retry.retries() > 0 {
+            arg.encode(&mut encoded);
+        }
+        let encoded = Bytes::from(encoded);
+        let mut arg = Some(arg);
+
+        loop_fn(0, move |attempt| {
+            let arg = match arg.take() {
+                Some(arg) => arg,
+                None => match Arg::decode(&query, &encoded) {
+                    Ok(arg) => arg,
+                    Err(error) => return Either::A(err(KTransError::IOError(Error::new(ErrorKind::InvalidData, error.to_string())))),
+                },
+            };
+            let call = KCall {addr, arg, meta: meta.clone(), priority, timeout: retry.timeout(timeout, attempt)};
+            Either::B(transact(&query_tx, &control_tx, &timer, call)
+                .then(move |result| {
+                    match result {
+                        Ok(res) => Ok(Loop::Break(res)),
+                        Err(KTransError::Timeout) if attempt < retry.retries() => {
+                            debug!("Retry query to: {}", addr);
+                            Ok(Loop::Continue(attempt + 1))
+                        },
+                        Err(error) => Err(error),
+                    }
+                }))
+        })
+    }
+}
+
+impl<Query, Arg, Res, Handler> KService<Query, Arg, Res, Handler>
+    where Query: 'static + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 'static + Serialize + DeserializeOwned + Debug + Send + KQueryArg<Query = Query>,
+          Res: 'static + Serialize + DeserializeOwned + Debug + Send + KQueryRes<Query = Query>,
+          Handler: 'static + Service<Request = Arg, Response = Res, Error = KError>,
+{
+    /// Thread-safe handle which can be used to make calls from other threads
+    pub fn sync_client(&self) -> KSyncClient<Arg, Res> {
+        KSyncClient::new(self, &self.handle)
+    }
+}
+
+/// Outgoing queries as a service
+///
+/// The request is a pair of node address and query argument.
+impl<Query, Arg, Res, Handler> Service for KService<Query, Arg, Res, Handler>
+    where Query: 'static + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 'static + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: 'static + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+          Handler: 'static + Service<Request = Arg, Response = Res, Error = KError>,
+{
+    type Request = (SocketAddr, Arg);
+    type Response = Res;
+    type Error = KTransError;
+    type Future = Box<dyn Future<Item = Res, Error = KTransError>>;
+
+    fn call(&self, (addr, arg): Self::Request) -> Self::Future {
+        Box::new(KService::call(self, addr, arg))
+    }
+}
+
+#[cfg(feature = "tower")]
+impl<Query, Arg, Res, Handler> tower_service::Service<(SocketAddr, Arg)> for KService<Query, Arg, Res, Handler>
+    where Query: 'static + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 'static + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: 'static + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+          Handler: 'static + Service<Request = Arg, Response = Res, Error = KError>,
+{
+    type Response = Res;
+    type Error = KTransError;
+    type Future = Box<dyn Future<Item = Res, Error = KTransError>>;
+
+    // the service isn't ready while the queue of outgoing queries is full
+    fn poll_ready(&mut self) -> Poll<(), KTransError> {
+        if self.queue.len.get() < self.options.max_queued {
+            return Ok(Async::Ready(()));
+        }
+        self.queue.block();
+        Ok(Async::NotReady)
+    }
+
+    fn call(&mut self, (addr, arg): (SocketAddr, Arg)) -> Self::Future {
+        Box::new(KService::call(self, addr, arg))
+    }
+}
+
+/// Single attempt of outgoing call
+struct KCall<Arg> {
+    addr: SocketAddr,
+    arg: Arg,
+    meta: KMeta,
+    priority: KPriority,
+    timeout: Duration,
+}
+
+fn transact<Arg, Res>(query_tx: &KQuerySender<Arg, Res>, control_tx: &KControlSender<Arg, Res>, timer: &Rc<dyn KTimer>, call: KCall<Arg>) -> impl Future<Item = Res, Error = KTransError> {
+    let KCall {addr, arg, meta, priority, timeout} = call;
+    let (res_tx, res_rx) = oneshot::channel();
+    let (tid_tx, tid_rx) = oneshot::channel();
+    let timer = timer.clone();
+    let control_tx = control_tx.clone();
+    let query_tx = query_tx.clone();
+
+    query_tx.send(KTransQuery(addr, arg, meta, priority, res_tx, tid_tx))
+        // the queries channel is closed by server only
+        .map_err(|_| KTransError::Shutdown)
+        .and_then(move |_| {
+            tid_rx.map_err(|_| KTransError::IOError(Error::new(ErrorKind::Other, "Send error")))
+                .and_then(|result| result)
+                .and_then(move |tid| {
+                    timer.timeout(timeout)
+                        .map_err(|err| KTransError::IOError(err))
+                        .map(Either::B)
+                        .select(res_rx.map_err(|_| KTransError::IOError(Error::new(ErrorKind::Other, "Recv error")))
+                                .map(Either::A))
+                        .map_err(|(err, _)| err)
+                        .and_then(move |(result, _)| {
+                            match result {
+                                Either::A(Ok(res)) => ok(res),
+                                Either::A(Err(error)) => err(error),
+                                Either::B(_) => {
+                                    let _ = control_tx.unbounded_send(KControl::Cancel(tid));
+                                    err(KTransError::Timeout)
+                                },
+                            }
+                        })
+                })
+        })
+}
+
+// The method of interceptor which is called for passing item
+type KHook<Arg, Res> = fn(&mut (dyn KInterceptor<Arg, Res> + 'static), KItem<Arg, Res>) -> Option<KItem<Arg, Res>>;
+
+// Pass item through the stack of interceptors using given hook
+fn intercept<Arg, Res>(interceptors: &mut [Box<dyn KInterceptor<Arg, Res>>], item: KItem<Arg, Res>, hook: KHook<Arg, Res>) -> Option<KItem<Arg, Res>> {
+    interceptors.iter_mut().try_fold(item, |item, interceptor| hook(&mut **interceptor, item))
+}
+
+/// Outgoing query waiting to be sent
+struct KQueued<Arg, Res> {
+    priority: KPriority,
+    seq: u64,
+    query: KTransQuery<Arg, Res>,
+}
+
+impl<Arg, Res> PartialEq for KQueued<Arg, Res> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl<Arg, Res> Eq for KQueued<Arg, Res> {}
+
+impl<Arg, Res> PartialOrd for KQueued<Arg, Res> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Arg, Res> Ord for KQueued<Arg, Res> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // higher priority first, then first in first out
+        self.priority.cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// The response with query method name
+type KReply<'s, Arg, Res> = Box<dyn Future<Item = (String, KItem<Arg, Res>), Error = ()> + 's>;
+
+// The message to send with query method name or foreign datagram
+type KOutgoing<Arg, Res> = Either<(String, KItem<Arg, Res>), KRaw>;
+
+struct KServer<'s, Transport, Query, Arg, Res, Handler> {
+    options: KOptions,
+    transport: Transport,
+    codec: KCodec<Query, Arg, Res>,
+    // names of methods for metrics and events
+    names: KQueryNames<Query>,
+    query_rx: Option<KQueryReceiver<Arg, Res>>,
+    control_rx: KControlReceiver<Arg, Res>,
+    closing: bool,
+    // foreign datagrams forwarding
+    raw_tx: Option<mpsc::UnboundedSender<KRaw>>,
+    raw_rx: Option<KRawReceiver>,
+    trans: KTrans<KPending<Query, Res>>,
+    active: Rc<Cell<usize>>,
+    queue: Rc<KQueueState>,
+    stats: Rc<RefCell<KStats>>,
+    limiter: Option<Rc<RefCell<KRateLimiter>>>,
+    voter: KAddrVoter,
+    external_addr: Rc<Cell<Option<SocketAddr>>>,
+    addr_watchers: Vec<mpsc::UnboundedSender<SocketAddr>>,
+    handler: Handler,
+    interceptors: Vec<Box<dyn KInterceptor<Arg, Res>>>,
+    observers: KObservers,
+    queued: BinaryHeap<KQueued<Arg, Res>>,
+    queued_seq: u64,
+    replies: FuturesUnordered<KReply<'s, Arg, Res>>,
+    outgoing: VecDeque<KOutgoing<Arg, Res>>,
+}
+
+impl<'s, Transport, Query, Arg, Res, Handler> KServer<'s, Transport, Query, Arg, Res, Handler>
+    where Transport: KTransport,
+          Query: 's + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 's + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: 's + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+          Handler: 's + Service<Request = Arg, Response = Res, Error = KError>,
+{
+    fn poll_control(&mut self) {
+        while let Ok(Async::Ready(Some(control))) = self.control_rx.poll() {
+            match control {
+                KControl::Cancel(trans_id) => {
+                    warn!("DHT Response timeout");
+                    if let Some(KPending {res_tx, query, ..}) = self.trans.end(&trans_id) {
+                        self.stats.borrow_mut().query(self.names.name(&query).unwrap_or(UNKNOWN_QUERY)).timeouts += 1;
+                        let _ = res_tx.send(Err(KTransError::Timeout));
+                    }
+                },
+                KControl::Demux(raw_tx, raw_rx) => {
+                    self.raw_tx = Some(raw_tx);
+                    self.raw_rx = Some(raw_rx);
+                },
+                KControl::Intercept(interceptor) => self.interceptors.push(interceptor),
+                KControl::Observe(sink) => self.observers.add(sink),
+                KControl::WatchAddr(addr_tx) => self.addr_watchers.push(addr_tx),
+                KControl::Shutdown => self.close(),
+            }
+        }
+    }
+
+    // Reject new queries and fail pending transactions
+    fn close(&mut self) {
+        if self.closing {
+            return;
+        }
+        info!("Shutting down");
+        self.closing = true;
+        if let Some(mut query_rx) = self.query_rx.take() {
+            query_rx.close();
+            while let Ok(Async::Ready(Some(KTransQuery(.., tid_tx)))) = query_rx.poll() {
+                let _ = tid_tx.send(Err(KTransError::Shutdown));
+            }
+        }
+        for KQueued {query: KTransQuery(.., tid_tx), ..} in self.queued.drain() {
+            let _ = tid_tx.send(Err(KTransError::Shutdown));
+        }
+        while let Some((_, KPending {res_tx, ..})) = self.trans.evict() {
+            let _ = res_tx.send(Err(KTransError::Shutdown));
+        }
+        self.active.set(0);
+        self.queue.set_len(0, self.options.max_queued);
+        self.raw_tx = None;
+        self.raw_rx = None;
+    }
+
+    // Returns true when some queries was taken
+    fn poll_queries(&mut self) -> Result<bool, Error> {
+        let mut query_rx = match self.query_rx.take() {
+            Some(query_rx) => query_rx,
+            None => return Ok(false),
+        };
+        let mut taken = false;
+        while self.queued.len() < self.options.max_queued {
+            match query_rx.poll() {
+                Ok(Async::Ready(Some(query))) => {
+                    let priority = query.3;
+                    self.queued_seq += 1;
+                    self.queued.push(KQueued { priority, seq: self.queued_seq, query });
+                    taken = true;
+                },
+                // all service handles is dropped
+                Ok(Async::Ready(None)) => return Ok(taken),
+                Ok(Async::NotReady) => break,
+                Err(_) => return Err(Error::new(ErrorKind::Other, "Query error")),
+            }
+        }
+        self.query_rx = Some(query_rx);
+        Ok(taken)
+    }
+
+    // Take next queued query which can be sent
+    fn dequeue(&mut self) -> Option<(String, KItem<Arg, Res>)> {
+        while self.make_room() {
+            let KQueued {query, ..} = self.queued.pop()?;
+            if let Some(item) = self.start_query(query) {
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    // Apply overflow policy when transaction pool is full, returns false when queries should wait
+    fn make_room(&mut self) -> bool {
+        while self.trans.is_full() {
+            match self.options.overflow {
+                KOverflow::Wait => return false,
+                KOverflow::Fail => {
+                    if let Some(KQueued {query: KTransQuery(addr, .., tid_tx), ..}) = self.queued.pop() {
+                        warn!("Transaction pool overflow, query to: {}", addr);
+                        let _ = tid_tx.send(Err(KTransError::Overflow));
+                    } else {
+                        return false;
+                    }
+                },
+                KOverflow::DropOldest => {
+                    if self.queued.is_empty() {
+                        return false;
+                    }
+                    if let Some((KId(addr, _), KPending {res_tx, ..})) = self.trans.evict() {
+                        warn!("Transaction pool overflow, drop query to: {}", addr);
+                        let _ = res_tx.send(Err(KTransError::Overflow));
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    // Start transaction of query, returns None when query is failed instead
+    fn start_query(&mut self, query: KTransQuery<Arg, Res>) -> Option<(String, KItem<Arg, Res>)> {
+        let KTransQuery(addr, arg, meta, _, res_tx, tid_tx) = query;
+        if self.is_blocked(&addr) {
+            debug!("Outgoing query to blocked address: {}", addr);
+            let _ = tid_tx.send(Err(KTransError::Blocked));
+            return None;
+        }
+        let trans_id = match self.trans.start(addr, KPending {res_tx, query: arg.query(), sent: Instant::now()}) {
+            Ok(trans_id) => trans_id,
+            Err(..) => {
+                warn!("No free transaction id, query to: {}", addr);
+                let _ = tid_tx.send(Err(KTransError::Overflow));
+                return None;
+            },
+        };
+        let _ = tid_tx.send(Ok(trans_id.clone()));
+        let item = KItem(trans_id.clone(), KData::Query(arg), meta);
+        match intercept(&mut self.interceptors, item, KInterceptor::outgoing_query) {
+            Some(item) => {
+                // the interceptor may rewrite the argument, so the response is expected to the query sent
+                let name = match item.1 {
+                    KData::Query(ref arg) => {
+                        let query = arg.query();
+                        let name = self.names.name(&query).unwrap_or(UNKNOWN_QUERY).to_string();
+                        if let Some(pending) = self.trans.get_mut(&trans_id) {
+                            pending.query = query;
+                        }
+                        name
+                    },
+                    _ => UNKNOWN_QUERY.to_string(),
+                };
+                self.stats.borrow_mut().query(&name).queries_sent += 1;
+                Some((name, item))
+            },
+            None => {
+                debug!("Outgoing query is dropped by interceptor: {}", addr);
+                if let Some(KPending {res_tx, ..}) = self.trans.end(&trans_id) {
+                    let _ = res_tx.send(Err(KTransError::Rejected));
+                }
+                None
+            },
+        }
+    }
+
+    // Check address against blocklist and count blocked ones
+    fn is_blocked(&self, addr: &SocketAddr) -> bool {
+        let blocked = self.options.blocklist.as_ref()
+            .map(|blocklist| blocklist.contains(&addr.ip()))
+            .unwrap_or(false);
+        if blocked {
+            self.stats.borrow_mut().blocked += 1;
+        }
+        blocked
+    }
+
+    fn forward(&mut self, raw: KRaw) {
+        let sent = match self.raw_tx {
+            Some(ref raw_tx) => raw_tx.unbounded_send(raw).is_ok(),
+            None => {
+                debug!("Drop foreign datagram from: {}", raw.0);
+                return;
+            },
+        };
+        if !sent {
+            // the stream of foreign datagrams is dropped
+            self.raw_tx = None;
+        }
+    }
+
+    // Take next datagram of another protocol
+    fn poll_raw(&mut self) -> Option<KRaw> {
+        match self.raw_rx.as_mut().map(|raw_rx| raw_rx.poll()) {
+            Some(Ok(Async::Ready(Some(raw)))) => return Some(raw),
+            Some(Ok(Async::NotReady)) | None => return None,
+            // the sink of foreign datagrams is dropped
+            Some(Ok(Async::Ready(None))) | Some(Err(_)) => (),
+        }
+        self.raw_rx = None;
+        None
+    }
+
+    fn dispatch(&mut self, item: KItem<Arg, Res>) {
+        let pending = match item.1 {
+            KData::Query(..) => None,
+            _ => self.trans.end(&item.0),
+        };
+        // the name is looked up once per message
+        let name = match (&item.1, pending.as_ref()) {
+            (KData::Query(arg), _) => Some(self.names.name(&arg.query()).unwrap_or(UNKNOWN_QUERY).to_string()),
+            (_, Some(pending)) => Some(self.names.name(&pending.query).unwrap_or(UNKNOWN_QUERY).to_string()),
+            // the response to unknown transaction
+            _ => None,
+        };
+        self.observe(KDirection::Inbound, &item, name.as_ref(), pending.as_ref());
+        self.count_inbound(&item, name.as_ref(), pending.as_ref());
+        if let (Some(ip), Some(..)) = ((item.2).ip, pending.as_ref()) {
+            // only responses to own queries is trusted
+            self.vote_addr((item.0).0.ip(), ip);
+        }
+        let KItem(trans_id, msg, _) = match item.1 {
+            KData::Query(..) => {
+                if self.throttle(&item, name.as_ref().map_or(UNKNOWN_QUERY, String::as_str)) {
+                    return;
+                }
+                match intercept(&mut self.interceptors, item, KInterceptor::inbound_query) {
+                    Some(item) => item,
+                    None => return,
+                }
+            },
+            _ => item,
+        };
+        match msg {
+            KData::Query(arg) => {
+                let name = name.unwrap_or_else(|| UNKNOWN_QUERY.to_string());
+                self.replies.push(Box::new(self.handler.call(arg).then(|result| {
+                    let resp = match result {
+                        Ok(res) => KData::Response(res),
+                        Err(err) => KData::Error(err),
+                    };
+                    Ok((name, KItem(trans_id, resp, KMeta::default())))
+                })));
+            },
+            KData::Response(res) => {
+                if let Some(KPending {res_tx, ..}) = pending {
+                    let _ = res_tx.send(Ok(res));
+                }
+            },
+            KData::Error(err) => {
+                warn!("Received KRPC error: {:?}", err);
+                if let Some(KPending {res_tx, ..}) = pending {
+                    let _ = res_tx.send(Err(KTransError::KError(err)));
+                }
+            },
+        }
+    }
+
+    fn vote_addr(&mut self, voter: IpAddr, addr: SocketAddr) {
+        if let Some(addr) = self.voter.vote(voter, addr) {
+            self.external_addr.set(Some(addr));
+            self.addr_watchers.retain(|addr_tx| addr_tx.unbounded_send(addr).is_ok());
+        }
+    }
+
+    // Check rate limit of inbound query, returns true when query is throttled
+    fn throttle(&mut self, item: &KItem<Arg, Res>, name: &str) -> bool {
+        let KItem(ref trans_id, ref msg, _) = *item;
+        let over_limit = match self.limiter {
+            Some(ref limiter) => {
+                let mut limiter = limiter.borrow_mut();
+                if limiter.check(&trans_id.0.ip(), Instant::now()) {
+                    return false;
+                }
+                limiter.limit().over_limit
+            },
+            None => return false,
+        };
+        debug!("Throttle query from: {}", trans_id.0);
+        self.stats.borrow_mut().throttled += 1;
+        if let (KOverLimit::Reply, KData::Query(..)) = (over_limit, msg) {
+            let error = KError(KErrorKind::Server, "Rate limit exceeded".into());
+            self.replies.push(Box::new(ok((name.to_string(), KItem(trans_id.clone(), KData::Error(error), KMeta::default())))));
+        }
+        true
+    }
+
+    fn count_inbound(&mut self, item: &KItem<Arg, Res>, name: Option<&String>, pending: Option<&KPending<Query, Res>>) {
+        let mut stats = self.stats.borrow_mut();
+        match (&item.1, name, pending) {
+            (KData::Query(..), Some(name), _) => stats.query(name).queries_received += 1,
+            (KData::Response(..), Some(name), Some(pending)) => {
+                let stats = stats.query(name);
+                stats.responses_received += 1;
+                stats.latency.observe(pending.sent.elapsed());
+            },
+            (KData::Error(KError(kind, _)), Some(name), Some(pending)) => {
+                let stats = stats.query(name);
+                *stats.errors_received.entry(*kind).or_insert(0) += 1;
+                stats.latency.observe(pending.sent.elapsed());
+            },
+            // the response to unknown transaction
+            _ => (),
+        }
+    }
+
+    fn count_decode_error(&mut self, addr: SocketAddr, buf: &[u8]) {
+        let name = match peek_header(buf) {
+            // the names from network is counted only when known to keep the number of metrics bounded
+            Some((_, Some(name))) => self.names.query(name.as_bytes()).map(|_| name),
+            Some((tid @ Some(..), None)) => match self.trans.get(&KId(addr, tid)) {
+                Some(pending) => self.names.name(&pending.query).map(String::from),
+                None => None,
+            },
+            _ => None,
+        };
+        let mut stats = self.stats.borrow_mut();
+        stats.decode_errors += 1;
+        stats.query(name.as_ref().map_or(UNKNOWN_QUERY, String::as_str)).decode_errors += 1;
+    }
+
+    // Broadcast message to observers
+    fn observe(&mut self, direction: KDirection, item: &KItem<Arg, Res>, name: Option<&String>, pending: Option<&KPending<Query, Res>>) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let KItem(KId(peer, ref tid), ref msg, _) = *item;
+        let kind = match *msg {
+            KData::Query(..) => KEventKind::Query,
+            KData::Response(..) => KEventKind::Response,
+            KData::Error(KError(kind, _)) => KEventKind::Error(kind),
+        };
+        self.observers.publish(KEvent {
+            direction,
+            peer,
+            tid: tid.clone(),
+            kind,
+            query: name.cloned(),
+            rtt: pending.map(|pending| pending.sent.elapsed()),
+        });
+    }
+
+    fn poll_replies(&mut self) {
+        while let Ok(Async::Ready(Some((name, item)))) = self.replies.poll() {
+            if let Some(item) = intercept(&mut self.interceptors, item, KInterceptor::outbound_response) {
+                match item.1 {
+                    KData::Error(KError(kind, _)) => *self.stats.borrow_mut().query(&name).errors_sent.entry(kind).or_insert(0) += 1,
+                    _ => self.stats.borrow_mut().query(&name).responses_sent += 1,
+                }
+                self.outgoing.push_back(Either::A((name, item)));
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Poll<(), Error> {
+        loop {
+            let item = if let Some(item) = self.outgoing.pop_front() {
+                item
+            } else if let Some(raw) = self.poll_raw() {
+                Either::B(raw)
+            } else if let Some(item) = self.dequeue() {
+                Either::A(item)
+            } else {
+                break;
+            };
+            let addr = match item {
+                Either::A((_, ref item)) => (item.0).0,
+                Either::B(ref raw) => raw.0,
+            };
+            if self.is_blocked(&addr) {
+                debug!("Drop datagram to blocked address: {}", addr);
+                continue;
+            }
+            let raw = match item {
+                Either::A((name, item)) => {
+                    // only the messages which isn't blocked is reported as sent
+                    self.observe(KDirection::Outbound, &item, Some(&name), None);
+                    let mut buf = Vec::new();
+                    let addr = self.codec.encode(Either::A(item), &mut buf);
+                    KRaw(addr, buf)
+                },
+                Either::B(raw) => raw,
+            };
+            if let AsyncSink::NotReady(raw) = self.transport.start_send(raw)? {
+                self.outgoing.push_front(Either::B(raw));
+                break;
+            }
+        }
+        self.transport.poll_complete()
+    }
+}
+
+impl<'s, Transport, Query, Arg, Res, Handler> Future for KServer<'s, Transport, Query, Arg, Res, Handler>
+    where Transport: KTransport,
+          Query: 's + Serialize + DeserializeOwned + Debug + Eq,
+          Arg: 's + Serialize + DeserializeOwned + Debug + KQueryArg<Query = Query>,
+          Res: 's + Serialize + DeserializeOwned + Debug + KQueryRes<Query = Query>,
+          Handler: 's + Service<Request = Arg, Response = Res, Error = KError>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.poll_control();
+        if self.closing {
+            // send the responses to already received queries
+            self.poll_replies();
+            let flushed = self.flush()?;
+            return Ok(if flushed.is_ready() && self.replies.is_empty() && self.outgoing.is_empty() {
+                info!("Shut down");
+                Async::Ready(())
+            } else {
+                Async::NotReady
+            });
+        }
+        loop {
+            match self.transport.poll() {
+                Ok(Async::Ready(Some(KRaw(addr, buf)))) => {
+                    if self.is_blocked(&addr) {
+                        debug!("Drop datagram from blocked address: {}", addr);
+                        continue;
+                    }
+                    if !is_krpc(&buf) {
+                        self.forward(KRaw(addr, buf));
+                        continue;
+                    }
+                    let buf = Bytes::from(buf);
+                    let decoded = {
+                        let trans = &self.trans;
+                        self.codec.decode_for(&addr, &buf, |id| trans.get(id).map(|pending| &pending.query))
+                    };
+                    match decoded {
+                        Ok(Either::A(item)) => self.dispatch(item),
+                        Ok(Either::B(raw)) => self.forward(raw),
+                        Err(KDecodeError::Response(trans_id, err)) => {
+                            warn!("Unexpected response from {}: {}", addr, err);
+                            self.count_decode_error(addr, &buf);
+                            if let Some(KPending {res_tx, ..}) = self.trans.end(&trans_id) {
+                                let _ = res_tx.send(Err(KTransError::BadResponse(err)));
+                            }
+                        },
+                        // malformed message should not stop the service
+                        Err(err) => {
+                            warn!("recv err: {}", Error::from(err));
+                            self.count_decode_error(addr, &buf);
+                        },
+                    }
+                },
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(err) => {
+                    error!("recv err: {}", err);
+                    return Err(err);
+                },
+            }
+        }
+        self.poll_replies();
+        loop {
+            let taken = self.poll_queries()?;
+            self.flush()?;
+            if !taken {
+                break;
+            }
+        }
+        self.active.set(self.trans.active());
+        self.queue.set_len(self.queued.len(), self.options.max_queued);
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    #[cfg(feature = "tower")]
+    use futures::{Future, Async, task};
+    #[cfg(feature = "tower")]
+    use futures::future::poll_fn;
+    use super::KRetry;
+    #[cfg(feature = "tower")]
+    use super::KQueueState;
+
+    #[test]
+    fn test_retry_timeout() {
+        let base = Duration::from_secs(2);
+
+        assert_eq!(KRetry::Fixed(3).timeout(base, 2), base);
+        assert_eq!(KRetry::Backoff(3).timeout(base, 0), base);
+        assert_eq!(KRetry::Backoff(3).timeout(base, 2), base * 4);
+        // the timeout is capped instead of overflow
+        assert_eq!(KRetry::Backoff(100).timeout(base, 40), Duration::from_secs(300));
+        assert_eq!(KRetry::Backoff(100).timeout(base, 99), Duration::from_secs(300));
+        assert_eq!(KRetry::Backoff(1).timeout(Duration::from_secs(600), 1), Duration::from_secs(600));
+    }
+
+    #[cfg(feature = "tower")]
+    #[test]
+    fn test_queue_block() {
+        let queue = KQueueState::default();
+        let mut polls = 0;
+        let wait = poll_fn(|| {
+            polls += 1;
+            queue.block();
+            queue.block();
+            if polls == 3 {
+                return Ok::<_, ()>(Async::Ready(()));
+            }
+            task::current().notify();
+            Ok(Async::NotReady)
+        });
+        assert_eq!(wait.wait(), Ok(()));
+        assert_eq!(queue.blocked.borrow().len(), 1);
 
-        query_tx.send(Either::A(KTransQuery(addr, arg, res_tx, tid_tx)))
-            .map_err(|_| KTransError::IOError(Error::new(ErrorKind::Other, "Send error")))
-            .and_then(move |_| {
-                tid_rx.map_err(|_| KTransError::IOError(Error::new(ErrorKind::Other, "Send error")))
-                    .and_then(move |tid| {
-                        Timeout::new(timeout, &handle).unwrap()
-                            .map_err(|err| KTransError::IOError(err))
-                            .map(Either::B)
-                            .select(res_rx.map_err(|_| KTransError::IOError(Error::new(ErrorKind::Other, "Recv error")))
-                                    .map(Either::A))
-                            .map_err(|(err, _)| err)
-                            .and_then(|(result, _)| {
-                                match result {
-                                    Either::A(Ok(res)) => Either::A(ok(res)),
-                                    Either::A(Err(error)) => Either::A(err(error)),
-                                    Either::B(_) => Either::B(cancel_tx.send(Either::B(tid))
-                                                              .then(|_| err(KTransError::Timeout))),
-                                }
-                            })
-                    })
-            })
+        // the free place notifies the waiting task
+        queue.set_len(0, 1);
+        assert!(queue.blocked.borrow().is_empty());
     }
 }
@@ -0,0 +1,368 @@
+use std::net::{IpAddr, SocketAddr};
+use std::io::{Error, Result};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use futures::{Future, Stream, Sink, Poll, StartSend, Async, AsyncSink};
+use futures::executor::{self, Notify};
+use futures::task::{self, Task};
+
+use tokio_core::reactor::{Handle, Core};
+
+use super::KRaw;
+use super::transport::{KTransport, KTimer, KMemoryHub, KMemoryTransport};
+
+/// Number of reactor turns without network activity after which the nodes is considered idle
+const SETTLE_TURNS: usize = 4;
+
+/// Behavior of simulated network
+#[derive(Debug, Clone)]
+pub struct KSimOptions {
+    /// Minimal delivery delay
+    pub latency: Duration,
+    /// Maximum random delay which is added to latency
+    ///
+    /// The datagrams may be reordered when it isn't zero.
+    pub jitter: Duration,
+    /// Probability of datagram loss from 0.0 to 1.0
+    pub loss: f64,
+    /// Probability of datagram to be held back by one more latency and jitter from 0.0 to 1.0
+    ///
+    /// The datagrams sent after the held one may overtake it.
+    pub reorder: f64,
+}
+
+impl Default for KSimOptions {
+    fn default() -> Self {
+        KSimOptions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            reorder: 0.0,
+        }
+    }
+}
+
+/// Counters of simulated network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KSimStats {
+    pub sent: u64,
+    pub delivered: u64,
+    /// Dropped due to packet loss
+    pub lost: u64,
+    /// Dropped due to network partition
+    pub partitioned: u64,
+    /// Held back by reordering
+    pub reordered: u64,
+}
+
+#[derive(Default)]
+struct KSimAlarm {
+    fired: Cell<bool>,
+    task: RefCell<Option<Task>>,
+}
+
+enum KSimAction {
+    Deliver(SocketAddr, KRaw),
+    Wake(Rc<KSimAlarm>),
+}
+
+struct KSimEvent {
+    deadline: Duration,
+    seq: u64,
+    action: KSimAction,
+}
+
+impl PartialEq for KSimEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl Eq for KSimEvent {}
+
+impl PartialOrd for KSimEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KSimEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // earliest first, then in order of scheduling
+        other.deadline.cmp(&self.deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct KSimState {
+    options: KSimOptions,
+    rng: XorShiftRng,
+    hub: KMemoryHub,
+    events: BinaryHeap<KSimEvent>,
+    last_seq: u64,
+    // virtual time since creation of network
+    now: Duration,
+    // partition groups, zero group by default
+    groups: HashMap<IpAddr, usize>,
+    last_group: usize,
+    stats: KSimStats,
+    // changed when datagram is sent, delivered or received and when timer is set
+    activity: u64,
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+impl KSimState {
+    fn group(&self, ip: &IpAddr) -> usize {
+        self.groups.get(ip).cloned().unwrap_or(0)
+    }
+
+    fn schedule(&mut self, delay: Duration, action: KSimAction) {
+        self.last_seq += 1;
+        self.events.push(KSimEvent {deadline: self.now + delay, seq: self.last_seq, action});
+    }
+
+    fn send(&mut self, from: SocketAddr, raw: KRaw) {
+        self.activity += 1;
+        self.stats.sent += 1;
+        if self.group(&from.ip()) != self.group(&raw.0.ip()) {
+            self.stats.partitioned += 1;
+            return;
+        }
+        if self.options.loss > 0.0 && self.rng.gen::<f64>() < self.options.loss {
+            self.stats.lost += 1;
+            return;
+        }
+        let jitter = duration_nanos(self.options.jitter);
+        let mut delay = if jitter > 0 {
+            let nanos = self.rng.gen_range(0, jitter);
+            self.options.latency + Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+        } else {
+            self.options.latency
+        };
+        if self.options.reorder > 0.0 && self.rng.gen::<f64>() < self.options.reorder {
+            self.stats.reordered += 1;
+            delay += self.options.latency + self.options.jitter;
+        }
+        self.schedule(delay, KSimAction::Deliver(from, raw));
+    }
+
+    // Fire all events of the earliest deadline if it isn't later than the given time
+    fn step(&mut self, until: Option<Duration>) -> bool {
+        let deadline = match self.events.peek() {
+            Some(event) if until.map(|until| event.deadline <= until).unwrap_or(true) => event.deadline,
+            _ => return false,
+        };
+        self.now = deadline;
+        while self.events.peek().map(|event| event.deadline == deadline).unwrap_or(false) {
+            match self.events.pop().unwrap().action {
+                KSimAction::Deliver(from, raw) => {
+                    self.activity += 1;
+                    self.stats.delivered += 1;
+                    self.hub.send(from, raw);
+                },
+                KSimAction::Wake(alarm) => {
+                    alarm.fired.set(true);
+                    if let Some(task) = alarm.task.borrow_mut().take() {
+                        task.notify();
+                    }
+                },
+            }
+        }
+        true
+    }
+}
+
+/// Simulated network for testing of many nodes
+///
+/// The datagrams is delivered using in-memory hub with configured latency, loss, reordering and partitions.
+/// The network has virtual clock which is moved by test using `advance` or `run`, and the timeouts of calls
+/// made by services over the network use the same clock. The clock is moved only when the nodes have
+/// nothing to do, all random decisions is made using seeded generator in order of sending, and the events
+/// which is due at the same time is fired in order of scheduling, so the same scenario gives the same results.
+#[derive(Clone)]
+pub struct KSimNetwork(Rc<RefCell<KSimState>>);
+
+impl KSimNetwork {
+    pub fn new(seed: u32, options: KSimOptions) -> Self {
+        KSimNetwork(Rc::new(RefCell::new(KSimState {
+            options,
+            // xorshift seed cannot be all zeros
+            rng: XorShiftRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]),
+            hub: KMemoryHub::new(),
+            events: BinaryHeap::new(),
+            last_seq: 0,
+            now: Duration::from_secs(0),
+            groups: HashMap::new(),
+            last_group: 0,
+            stats: KSimStats::default(),
+            activity: 0,
+        })))
+    }
+
+    /// Create transport bound to the given address
+    pub fn bind(&self, addr: &SocketAddr) -> Result<KSimTransport> {
+        let inner = self.0.borrow().hub.bind(addr)?;
+        Ok(KSimTransport {network: self.clone(), inner})
+    }
+
+    pub fn set_options(&self, options: KSimOptions) {
+        self.0.borrow_mut().options = options;
+    }
+
+    /// Isolate nodes with given addresses from the rest of network
+    pub fn partition(&self, ips: &[IpAddr]) {
+        let mut state = self.0.borrow_mut();
+        state.last_group += 1;
+        let group = state.last_group;
+        for ip in ips {
+            state.groups.insert(*ip, group);
+        }
+    }
+
+    /// Remove all partitions
+    pub fn heal(&self) {
+        self.0.borrow_mut().groups.clear();
+    }
+
+    pub fn stats(&self) -> KSimStats {
+        self.0.borrow().stats
+    }
+
+    /// Virtual time since creation of network
+    pub fn now(&self) -> Duration {
+        self.0.borrow().now
+    }
+
+    /// Move the clock by the given time firing all events which is due
+    pub fn advance(&self, core: &mut Core, duration: Duration) {
+        let until = self.now() + duration;
+        self.settle(core);
+        while self.0.borrow_mut().step(Some(until)) {
+            self.settle(core);
+        }
+        self.0.borrow_mut().now = until;
+        self.settle(core);
+    }
+
+    /// Run the future on the core moving the clock until it is resolved
+    ///
+    /// Panics when the future isn't resolved and there is no more events in network.
+    pub fn run<F: Future>(&self, core: &mut Core, future: F) -> ::std::result::Result<F::Item, F::Error> {
+        let notify = Arc::new(KSimNotify);
+        let mut future = executor::spawn(future);
+        loop {
+            // the future is polled on every turn, so it doesn't need notifications
+            let mut idle = 0;
+            while idle < SETTLE_TURNS {
+                if let Async::Ready(item) = future.poll_future_notify(&notify, 0)? {
+                    return Ok(item);
+                }
+                idle = if self.turn(core) { 0 } else { idle + 1 };
+            }
+            if !self.0.borrow_mut().step(None) {
+                panic!("Simulated network is stalled");
+            }
+        }
+    }
+
+    // Turn the core until the nodes is idle
+    fn settle(&self, core: &mut Core) {
+        let mut idle = 0;
+        while idle < SETTLE_TURNS {
+            idle = if self.turn(core) { 0 } else { idle + 1 };
+        }
+    }
+
+    // Turn the core once without waiting and tell whether the nodes used network
+    fn turn(&self, core: &mut Core) -> bool {
+        let activity = self.0.borrow().activity;
+        core.turn(Some(Duration::from_millis(0)));
+        self.0.borrow().activity != activity
+    }
+}
+
+impl KTimer for KSimNetwork {
+    fn timeout(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = Error>> {
+        let alarm = Rc::new(KSimAlarm::default());
+        let mut state = self.0.borrow_mut();
+        state.activity += 1;
+        state.schedule(duration, KSimAction::Wake(alarm.clone()));
+        Box::new(KSimTimeout(alarm))
+    }
+}
+
+struct KSimNotify;
+
+impl Notify for KSimNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+struct KSimTimeout(Rc<KSimAlarm>);
+
+impl Future for KSimTimeout {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if self.0.fired.get() {
+            return Ok(Async::Ready(()));
+        }
+        *self.0.task.borrow_mut() = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// Transport which is bound to simulated network
+pub struct KSimTransport {
+    network: KSimNetwork,
+    inner: KMemoryTransport,
+}
+
+impl Stream for KSimTransport {
+    type Item = KRaw;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<KRaw>, Error> {
+        let result = self.inner.poll();
+        // only the received datagram counts, polling of idle node doesn't
+        if let Ok(Async::Ready(Some(..))) = result {
+            self.network.0.borrow_mut().activity += 1;
+        }
+        result
+    }
+}
+
+impl Sink for KSimTransport {
+    type SinkItem = KRaw;
+    type SinkError = Error;
+
+    fn start_send(&mut self, raw: KRaw) -> StartSend<KRaw, Error> {
+        let from = self.inner.local_addr()?;
+        self.network.0.borrow_mut().send(from, raw);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl KTransport for KSimTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn timer(&self, _handle: &Handle) -> Rc<dyn KTimer> {
+        Rc::new(self.network.clone())
+    }
+}
@@ -1,50 +1,166 @@
 use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+
+use rand::{Rng, thread_rng};
 
 use super::{KTransId, KId};
 
-type TransId = u16;
-type TransKey = (SocketAddr, TransId);
-type TransPool<Data> = HashMap<TransKey, Data>;
+/// Default length of transaction id in bytes
+pub const DEFAULT_TID_LEN: usize = 4;
+
+// The ids of node are almost exhausted when so many random ones collides
+const MAX_TID_ATTEMPTS: usize = 64;
+
+type TransKey = (SocketAddr, KTransId);
+type TransSeq = u64;
+type TransPool<Data> = HashMap<TransKey, (TransSeq, Data)>;
+type TransOrder = VecDeque<(TransSeq, TransKey)>;
 
 pub struct KTrans<Data> {
-    last_tid: TransId,
+    tid_len: usize,
+    limit: usize,
+    rng: Box<dyn Rng>,
     pool: TransPool<Data>,
+    // start order of transactions including already ended
+    order: TransOrder,
+    last_seq: TransSeq,
 }
 
 impl<Data> KTrans<Data> {
     pub fn new() -> Self {
-        KTrans {last_tid: 0, pool: HashMap::new()}
+        KTrans::with_tid_len(DEFAULT_TID_LEN)
+    }
+
+    /// Create transaction pool which generates ids of given length
+    pub fn with_tid_len(tid_len: usize) -> Self {
+        assert!(tid_len > 0, "Transaction id cannot be empty");
+        KTrans {
+            tid_len,
+            limit: usize::MAX,
+            rng: Box::new(thread_rng()),
+            pool: HashMap::new(),
+            order: VecDeque::new(),
+            last_seq: 0,
+        }
+    }
+
+    /// Set maximum number of outstanding transactions
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Use specific random generator for transaction ids
+    pub fn with_rng<R: Rng + 'static>(mut self, rng: R) -> Self {
+        self.rng = Box::new(rng);
+        self
     }
 
     pub fn active(&self) -> usize {
         self.pool.len()
     }
 
-    pub fn start(&mut self, addr: SocketAddr, data: Data) -> KId {
-        self.last_tid += 1;
-        let tid = self.last_tid;
-        self.pool.insert((addr, tid), data);
-        KId(addr, Some(KTransId(vec![(tid >> 8) as u8, tid as u8])))
+    /// Check if transactions limit is reached
+    ///
+    /// Nothing prevents to start transactions over the limit, so the caller should check it first.
+    pub fn is_full(&self) -> bool {
+        self.pool.len() >= self.limit
+    }
+
+    /// Start new transaction with random id
+    ///
+    /// The id is never the same as of any outstanding transaction with the same node.
+    /// Returns the data back when no free id is found in a limited number of attempts.
+    pub fn start(&mut self, addr: SocketAddr, data: Data) -> Result<KId, Data> {
+        let mut buf = vec![0u8; self.tid_len];
+        for _ in 0..MAX_TID_ATTEMPTS {
+            self.rng.fill_bytes(&mut buf);
+            let tid = KTransId::from(&buf[..]);
+            let key = (addr, tid.clone());
+            if !self.pool.contains_key(&key) {
+                self.last_seq += 1;
+                self.order.push_back((self.last_seq, key.clone()));
+                self.pool.insert(key, (self.last_seq, data));
+                return Ok(KId(addr, Some(tid)));
+            }
+            debug!("Transaction id collision with: {}", addr);
+        }
+        Err(data)
+    }
+
+    /// Data of outstanding transaction
+    pub fn get(&self, trans: &KId) -> Option<&Data> {
+        match *trans {
+            KId(addr, Some(ref tid)) => self.pool.get(&(addr, tid.clone())).map(|(_, data)| data),
+            _ => None,
+        }
+    }
+
+    /// Mutable data of outstanding transaction
+    pub fn get_mut(&mut self, trans: &KId) -> Option<&mut Data> {
+        match *trans {
+            KId(addr, Some(ref tid)) => self.pool.get_mut(&(addr, tid.clone())).map(|entry| &mut entry.1),
+            _ => None,
+        }
     }
 
     pub fn end(&mut self, trans: &KId) -> Option<Data> {
-        if let &KId(addr, Some(KTransId(ref tid))) = trans {
-            if tid.len() == 2 {
-                let tid = ((tid[0] as u16) << 8) | (tid[1] as u16);
-                return self.pool.remove(&(addr, tid))
+        if let &KId(addr, Some(ref tid)) = trans {
+            if let Some((_, data)) = self.pool.remove(&(addr, tid.clone())) {
+                self.compact();
+                return Some(data);
+            }
+        }
+        None
+    }
+
+    /// Forcibly end the oldest outstanding transaction
+    pub fn evict(&mut self) -> Option<(KId, Data)> {
+        while let Some((seq, key)) = self.order.pop_front() {
+            if self.pool.get(&key).map(|&(cur_seq, _)| cur_seq == seq).unwrap_or(false) {
+                let (_, data) = self.pool.remove(&key).unwrap();
+                let (addr, tid) = key;
+                return Some((KId(addr, Some(tid)), data));
             }
         }
         None
     }
+
+    // drop ended transactions from order queue when it grows too much
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.pool.len() + 16 {
+            let pool = &self.pool;
+            self.order.retain(|&(seq, ref key)| {
+                pool.get(key).map(|&(cur_seq, _)| cur_seq == seq).unwrap_or(false)
+            });
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use rand::Rng;
     use super::{KId, KTrans, KTransId};
 
     type Trans = KTrans<u32>;
 
+    /// Generator which replays predefined bytes
+    struct ReplayRng(Vec<u8>);
+
+    impl Rng for ReplayRng {
+        fn next_u32(&mut self) -> u32 {
+            let mut bytes = [0u8; 4];
+            self.fill_bytes(&mut bytes);
+            bytes.iter().fold(0, |value, &byte| value << 8 | byte as u32)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest.iter_mut() {
+                *byte = self.0.remove(0);
+            }
+        }
+    }
+
     #[test]
     pub fn test_trans_mgr() {
         let mut trans = Trans::new();
@@ -52,27 +168,97 @@ mod tests {
         let a1 = "0.0.0.0:1234".parse().unwrap();
         let a2 = "127.0.0.1:6881".parse().unwrap();
 
-        let t1 = trans.start(a1, 1234);
-        assert_eq!(t1, KId(a1, Some(KTransId(vec![0, 1]))));
-        
-        let t2 = trans.start(a2, 567);
-        assert_eq!(t2, KId(a2, Some(KTransId(vec![0, 2]))));
-        
-        let t3 = trans.start(a1, 123);
-        assert_eq!(t3, KId(a1, Some(KTransId(vec![0, 3]))));
+        let t1 = trans.start(a1, 1234).unwrap();
+        let t2 = trans.start(a2, 567).unwrap();
+        let t3 = trans.start(a1, 123).unwrap();
+
+        assert_eq!(trans.active(), 3);
+        assert!(t1 != t3);
+
+        for t in &[&t1, &t2, &t3] {
+            if let &&KId(_, Some(KTransId(ref tid))) = t {
+                assert_eq!(tid.len(), 4);
+            } else {
+                panic!("Missing transaction id");
+            }
+        }
 
         let d1 = trans.end(&t1);
         assert_eq!(d1, Some(1234));
 
-        let t4 = KId(a2, Some(KTransId(vec![0, 4])));
+        let t4 = KId(a2, t1.1.clone());
         let d4 = trans.end(&t4);
         assert_eq!(d4, None);
 
         let d3 = trans.end(&t3);
         assert_eq!(d3, Some(123));
 
-        let t2 = KId(a2, Some(KTransId(vec![0, 2])));
         let d2 = trans.end(&t2);
         assert_eq!(d2, Some(567));
+
+        assert_eq!(trans.active(), 0);
+    }
+
+    #[test]
+    pub fn test_trans_collision() {
+        let mut trans = Trans::with_tid_len(2)
+            .with_rng(ReplayRng(vec![0, 1, 0, 1, 0, 1, 0, 2, 0, 1]));
+
+        let a1 = "127.0.0.1:6881".parse().unwrap();
+        let a2 = "127.0.0.1:6882".parse().unwrap();
+
+        let t1 = trans.start(a1, 1).unwrap();
+        assert_eq!(t1, KId(a1, Some(KTransId::from(&[0, 1][..]))));
+
+        // same id may be used with another node
+        let t2 = trans.start(a2, 2).unwrap();
+        assert_eq!(t2, KId(a2, Some(KTransId::from(&[0, 1][..]))));
+
+        // but never with the same node while transaction is active
+        let t3 = trans.start(a1, 3).unwrap();
+        assert_eq!(t3, KId(a1, Some(KTransId::from(&[0, 2][..]))));
+
+        assert_eq!(trans.end(&t1), Some(1));
+
+        let t4 = trans.start(a1, 4).unwrap();
+        assert_eq!(t4, KId(a1, Some(KTransId::from(&[0, 1][..]))));
+    }
+
+    #[test]
+    pub fn test_trans_evict() {
+        let mut trans = Trans::new().with_limit(2);
+
+        let a1 = "127.0.0.1:6881".parse().unwrap();
+        let a2 = "127.0.0.1:6882".parse().unwrap();
+
+        assert_eq!(trans.evict(), None);
+
+        let t1 = trans.start(a1, 1).unwrap();
+        assert!(!trans.is_full());
+        let t2 = trans.start(a2, 2).unwrap();
+        assert!(trans.is_full());
+
+        assert_eq!(trans.evict(), Some((t1, 1)));
+        assert!(!trans.is_full());
+
+        let t3 = trans.start(a1, 3).unwrap();
+        assert_eq!(trans.end(&t2), Some(2));
+
+        assert_eq!(trans.evict(), Some((t3, 3)));
+        assert_eq!(trans.evict(), None);
+        assert_eq!(trans.active(), 0);
+    }
+
+    #[test]
+    pub fn test_trans_exhausted() {
+        let mut trans = Trans::with_tid_len(1)
+            .with_rng(ReplayRng(vec![7; 65]));
+
+        let a1 = "127.0.0.1:6881".parse().unwrap();
+
+        assert_eq!(trans.start(a1, 1), Ok(KId(a1, Some(KTransId::from(&[7][..])))));
+        // the generator gives only used ids
+        assert_eq!(trans.start(a1, 2), Err(2));
+        assert_eq!(trans.active(), 1);
     }
 }
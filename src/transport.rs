@@ -0,0 +1,256 @@
+use std::net::{self, SocketAddr};
+use std::io::{Error, ErrorKind, Result};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::{Future, Stream, Sink, Poll, StartSend, Async, AsyncSink};
+use futures::unsync::mpsc;
+
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_core::net::{UdpSocket, UdpCodec, UdpFramed};
+
+use super::KRaw;
+
+/// Datagram transport which the service runs over
+///
+/// The stream gives received datagrams with source address and the sink takes datagrams with destination address.
+pub trait KTransport: Stream<Item = KRaw, Error = Error> + Sink<SinkItem = KRaw, SinkError = Error> {
+    /// The address which transport is bound to
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// Timer for timeouts of calls made over the transport
+    ///
+    /// Reactor timer by default, simulated transports may use their own clock.
+    fn timer(&self, handle: &Handle) -> Rc<dyn KTimer> {
+        Rc::new(KReactorTimer(handle.clone()))
+    }
+}
+
+/// Source of timeouts
+pub trait KTimer {
+    /// Future which is resolved when the given time is elapsed
+    fn timeout(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = Error>>;
+}
+
+struct KReactorTimer(Handle);
+
+impl KTimer for KReactorTimer {
+    fn timeout(&self, duration: Duration) -> Box<dyn Future<Item = (), Error = Error>> {
+        match Timeout::new(duration, &self.0) {
+            Ok(timeout) => Box::new(timeout),
+            Err(err) => Box::new(::futures::future::err(err)),
+        }
+    }
+}
+
+struct KRawCodec;
+
+impl UdpCodec for KRawCodec {
+    type In = KRaw;
+    type Out = KRaw;
+
+    fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> Result<Self::In> {
+        Ok(KRaw(*addr, buf.into()))
+    }
+
+    fn encode(&mut self, KRaw(addr, buf): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend(buf);
+        addr
+    }
+}
+
+/// Transport over UDP socket
+pub struct KUdpTransport(UdpFramed<KRawCodec>);
+
+impl KUdpTransport {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> Result<Self> {
+        UdpSocket::bind(addr, handle).map(KUdpTransport::new)
+    }
+
+    /// Use already bound socket
+    pub fn from_socket(socket: net::UdpSocket, handle: &Handle) -> Result<Self> {
+        UdpSocket::from_socket(socket, handle).map(KUdpTransport::new)
+    }
+
+    pub fn new(socket: UdpSocket) -> Self {
+        KUdpTransport(socket.framed(KRawCodec))
+    }
+}
+
+impl Stream for KUdpTransport {
+    type Item = KRaw;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<KRaw>, Error> {
+        self.0.poll()
+    }
+}
+
+impl Sink for KUdpTransport {
+    type SinkItem = KRaw;
+    type SinkError = Error;
+
+    fn start_send(&mut self, raw: KRaw) -> StartSend<KRaw, Error> {
+        self.0.start_send(raw)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.0.poll_complete()
+    }
+}
+
+impl KTransport for KUdpTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.0.get_ref().local_addr()
+    }
+}
+
+/// First port which is allocated by hub for unspecified port
+const FIRST_DYNAMIC_PORT: u16 = 49152;
+
+struct KMemoryNodes {
+    nodes: HashMap<SocketAddr, mpsc::UnboundedSender<KRaw>>,
+    next_port: u16,
+}
+
+/// In-process network for transports which doesn't use OS sockets
+///
+/// Useful to run many nodes in tests. The datagrams to unknown addresses is silently dropped like in UDP.
+#[derive(Clone)]
+pub struct KMemoryHub(Rc<RefCell<KMemoryNodes>>);
+
+impl Default for KMemoryHub {
+    fn default() -> Self {
+        KMemoryHub::new()
+    }
+}
+
+impl KMemoryHub {
+    pub fn new() -> Self {
+        KMemoryHub(Rc::new(RefCell::new(KMemoryNodes {
+            nodes: HashMap::new(),
+            next_port: FIRST_DYNAMIC_PORT,
+        })))
+    }
+
+    /// Create transport bound to the given address
+    ///
+    /// The zero port means any free port. The address should be specified
+    /// because it is used as source address of sent datagrams.
+    pub fn bind(&self, addr: &SocketAddr) -> Result<KMemoryTransport> {
+        if addr.ip().is_unspecified() {
+            return Err(Error::new(ErrorKind::AddrNotAvailable, "Unspecified address"));
+        }
+        let mut hub = self.0.borrow_mut();
+        let mut addr = *addr;
+        if addr.port() == 0 {
+            let start_port = hub.next_port;
+            loop {
+                addr.set_port(hub.next_port);
+                hub.next_port = hub.next_port.checked_add(1).unwrap_or(FIRST_DYNAMIC_PORT);
+                if !hub.nodes.contains_key(&addr) {
+                    break;
+                }
+                if hub.next_port == start_port {
+                    return Err(Error::new(ErrorKind::AddrInUse, "No free ports"));
+                }
+            }
+        } else if hub.nodes.contains_key(&addr) {
+            return Err(Error::new(ErrorKind::AddrInUse, "Address already in use"));
+        }
+        let (tx, rx) = mpsc::unbounded();
+        hub.nodes.insert(addr, tx);
+        Ok(KMemoryTransport {hub: self.clone(), addr, rx})
+    }
+
+    /// Deliver datagram to the transport bound to destination address
+    pub fn send(&self, from: SocketAddr, KRaw(to, buf): KRaw) {
+        if let Some(tx) = self.0.borrow().nodes.get(&to) {
+            let _ = tx.unbounded_send(KRaw(from, buf));
+        }
+    }
+
+    fn unbind(&self, addr: &SocketAddr) {
+        self.0.borrow_mut().nodes.remove(addr);
+    }
+}
+
+/// Transport which is bound to in-memory hub
+pub struct KMemoryTransport {
+    hub: KMemoryHub,
+    addr: SocketAddr,
+    rx: mpsc::UnboundedReceiver<KRaw>,
+}
+
+impl Stream for KMemoryTransport {
+    type Item = KRaw;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<KRaw>, Error> {
+        self.rx.poll().map_err(|_| Error::new(ErrorKind::Other, "Hub error"))
+    }
+}
+
+impl Sink for KMemoryTransport {
+    type SinkItem = KRaw;
+    type SinkError = Error;
+
+    fn start_send(&mut self, raw: KRaw) -> StartSend<KRaw, Error> {
+        self.hub.send(self.addr, raw);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl KTransport for KMemoryTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for KMemoryTransport {
+    fn drop(&mut self) {
+        self.hub.unbind(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use futures::{Future, Stream, Sink};
+    use super::{KMemoryHub, KTransport, KRaw};
+
+    #[test]
+    pub fn test_memory_hub() {
+        let hub = KMemoryHub::new();
+
+        let t1 = hub.bind(&"10.0.0.1:6881".parse().unwrap()).unwrap();
+        let t2 = hub.bind(&"10.0.0.2:0".parse().unwrap()).unwrap();
+        let t3 = hub.bind(&"10.0.0.2:0".parse().unwrap()).unwrap();
+
+        let a1 = t1.local_addr().unwrap();
+        let a2 = t2.local_addr().unwrap();
+        let a3 = t3.local_addr().unwrap();
+
+        assert_eq!(a2, "10.0.0.2:49152".parse().unwrap());
+        assert_eq!(a3, "10.0.0.2:49153".parse().unwrap());
+
+        assert_eq!(hub.bind(&a1).err().unwrap().kind(), ErrorKind::AddrInUse);
+        assert_eq!(hub.bind(&"0.0.0.0:0".parse().unwrap()).err().unwrap().kind(), ErrorKind::AddrNotAvailable);
+
+        let t1 = t1.send(KRaw(a2, b"hello".to_vec())).wait().unwrap();
+        let (raw, t2) = t2.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(raw, Some(KRaw(a1, b"hello".to_vec())));
+
+        // the address is released when transport is dropped
+        drop(t1);
+        let _ = t2.send(KRaw(a1, b"lost".to_vec())).wait().unwrap();
+        let t1 = hub.bind(&a1).unwrap();
+        assert_eq!(t1.local_addr().unwrap(), a1);
+    }
+}
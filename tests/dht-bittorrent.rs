@@ -3,21 +3,27 @@ extern crate futures;
 extern crate tokio_core;
 extern crate tokio_service;
 extern crate tokio_krpc;
+#[cfg(feature = "tower")]
+extern crate tower_service;
 
 #[macro_use]
 extern crate log;
 
-use std::net::SocketAddr;
+use std::net::{SocketAddr, UdpSocket};
 use std::io::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::thread;
 
-use futures::{Future};
+use futures::{Future, Stream, Sink};
 use futures::future::{ok, err};
+#[cfg(feature = "tower")]
+use futures::{Async, future::poll_fn};
+use futures::unsync::oneshot;
 
-use tokio_core::reactor::{Handle, Core};
+use tokio_core::reactor::{Handle, Core, Timeout};
 use tokio_service::Service;
 
-use tokio_krpc::{KError, KErrorKind, KService, KTransError, KOptions};
+use tokio_krpc::{KRaw, KExtra, KItem, KId, KData, KInterceptor, KDirection, KEventKind, KMemoryHub, KFamily, KDualService, KError, KErrorKind, KService, KTransError, KOptions, KCallOptions, KRetry, KPriority, KOverflow, KRateLimit, KOverLimit, KBlocklist};
 use tokio_krpc::dht::bittorrent::{BtDhtId, BtDhtQuery, BtDhtArg, BtDhtRes};
 
 #[derive(Clone)]
@@ -39,9 +45,9 @@ impl Service for BtDhtHandler {
     
     fn call(&self, arg: Self::Request) -> Self::Future {
         Box::new(match arg {
-            BtDhtArg::Ping {id} => {
+            BtDhtArg::Ping {id, ..} => {
                 info!("Received ping query from: {:?}", id);
-                ok(BtDhtRes::Pong {id: self.node_id})
+                ok(BtDhtRes::Pong {id: self.node_id, extra: KExtra::new()})
             },
             _ => {
                 err(KError(KErrorKind::Method, "Method unimplemented".into()))
@@ -65,19 +71,23 @@ pub enum BtDhtError {
 
 impl<'s> BtDhtService {
     pub fn new(node_id: BtDhtId, addr: &SocketAddr, handle: &Handle) -> (Self, impl Future<Item = (), Error = Error> + 's) {
+        let options = KOptions { timeout: Duration::from_secs(2), ..KOptions::default() };
+        BtDhtService::with_options(node_id, addr, handle, options)
+    }
+
+    pub fn with_options(node_id: BtDhtId, addr: &SocketAddr, handle: &Handle, options: KOptions) -> (Self, impl Future<Item = (), Error = Error> + 's) {
         let handler = BtDhtHandler::new(node_id);
-        let options = KOptions { timeout: Duration::from_secs(2) };
         let (service, thread) = KService::new(handler, addr, handle, options);
         (BtDhtService {node_id, service}, thread)
     }
 
     pub fn ping_node(&self, addr: SocketAddr) -> impl Future<Item = BtDhtId, Error = BtDhtError> + 's {
         info!("Send ping query to: {:?}", addr);
-        self.service.call(addr, BtDhtArg::Ping {id: self.node_id})
+        self.service.call(addr, BtDhtArg::Ping {id: self.node_id, extra: KExtra::new()})
             .map_err(BtDhtError::TransError)
             .and_then(move |res| {
                 match res {
-                    BtDhtRes::Pong {id} => {
+                    BtDhtRes::Pong {id, ..} => {
                         info!("Received ping response from: {:?} with id: {:?}", addr, id);
                         ok(id)
                     },
@@ -93,6 +103,13 @@ impl<'s> BtDhtService {
     }
 }
 
+/// Bound socket which never responds
+fn silent_node() -> (UdpSocket, SocketAddr) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    (socket, addr)
+}
+
 #[test]
 fn test_ping_query() {
     let mut core = Core::new().unwrap();
@@ -122,3 +139,715 @@ fn test_ping_query() {
                    }))
              .map_err(|_| ())).unwrap();
 }
+
+#[test]
+fn test_ping_timeout() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (dead_socket, dead_addr) = silent_node();
+
+    let (node_service, node_server) = BtDhtService::new(BtDhtId::new(), &node_addr, &handle);
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let options = KCallOptions {
+        timeout: Some(Duration::from_millis(100)),
+        retry: Some(KRetry::Fixed(2)),
+        ..KCallOptions::default()
+    };
+
+    match core.run(node_service.service.call_with(dead_addr, BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()}, options)) {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    // the query and two retries of the same ping
+    dead_socket.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 256];
+    let mut received = 0;
+    while let Ok((len, _)) = dead_socket.recv_from(&mut buf) {
+        assert!(buf[..len].windows(6).any(|window| window == b"4:ping"));
+        received += 1;
+    }
+    assert_eq!(received, 3);
+}
+
+#[test]
+fn test_call_priority() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let hub = KMemoryHub::new();
+    let options = KOptions { timeout: Duration::from_millis(50), max_active: 1, ..KOptions::default() };
+
+    let node_transport = hub.bind(&"10.0.0.1:6881".parse().unwrap()).unwrap();
+    let silent_transport = hub.bind(&"10.0.0.2:6881".parse().unwrap()).unwrap();
+    let silent_addr = "10.0.0.2:6881".parse().unwrap();
+
+    let (node_service, node_server) = KService::with_transport(BtDhtHandler::new(BtDhtId::new()), node_transport, &handle, options).unwrap();
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let ids: Vec<_> = (0..4).map(|_| BtDhtId::new()).collect();
+    let call = |id, priority| {
+        let options = KCallOptions { priority, ..KCallOptions::default() };
+        node_service.call_with(silent_addr, BtDhtArg::Ping {id, extra: KExtra::new()}, options).then(|_| Ok(()))
+    };
+
+    // the first query takes the only place in transaction pool
+    handle.spawn(call(ids[0], KPriority::Normal));
+    core.turn(Some(Duration::from_millis(10)));
+    core.turn(Some(Duration::from_millis(10)));
+    assert_eq!(node_service.active(), 1);
+
+    // so the rest is queued
+    handle.spawn(call(ids[1], KPriority::Low));
+    handle.spawn(call(ids[2], KPriority::Normal));
+    handle.spawn(call(ids[3], KPriority::High));
+
+    let raws = core.run(silent_transport.take(4).collect()).unwrap();
+    let order: Vec<_> = raws.iter().map(|KRaw(_, buf)| {
+        ids.iter().position(|id| buf.windows(20).any(|bytes| bytes == id.as_ref())).unwrap()
+    }).collect();
+    assert_eq!(order, vec![0, 3, 2, 1]);
+}
+
+#[test]
+fn test_trans_overflow() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (_dead_socket, dead_addr) = silent_node();
+
+    let options = KOptions {
+        timeout: Duration::from_millis(100),
+        max_active: 1,
+        overflow: KOverflow::Fail,
+        ..KOptions::default()
+    };
+    let (node_service, node_server) = BtDhtService::with_options(BtDhtId::new(), &node_addr, &handle, options);
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()};
+
+    let (first, second) = core.run(node_service.service.call(dead_addr, ping.clone()).then(Ok::<_, ()>)
+                                   .join(node_service.service.call(dead_addr, ping).then(Ok::<_, ()>))).unwrap();
+
+    match first {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match second {
+        Err(KTransError::Overflow) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    // let server handle the cancellation of timed out transaction
+    core.turn(Some(Duration::from_millis(10)));
+    assert_eq!(node_service.service.active(), 0);
+}
+
+#[test]
+fn test_trans_drop_oldest() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (_dead_socket, dead_addr) = silent_node();
+
+    let options = KOptions {
+        timeout: Duration::from_millis(100),
+        max_active: 1,
+        overflow: KOverflow::DropOldest,
+        ..KOptions::default()
+    };
+    let (node_service, node_server) = BtDhtService::with_options(BtDhtId::new(), &node_addr, &handle, options);
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()};
+
+    let (first, second) = core.run(node_service.service.call(dead_addr, ping.clone()).then(Ok::<_, ()>)
+                                   .join(node_service.service.call(dead_addr, ping).then(Ok::<_, ()>))).unwrap();
+
+    match first {
+        Err(KTransError::Overflow) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match second {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    core.turn(Some(Duration::from_millis(10)));
+    assert_eq!(node_service.service.active(), 0);
+}
+
+#[test]
+fn test_trans_wait() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (dead_socket, dead_addr) = silent_node();
+
+    let options = KOptions {
+        timeout: Duration::from_millis(100),
+        max_active: 1,
+        overflow: KOverflow::Wait,
+        ..KOptions::default()
+    };
+    let (node_service, node_server) = BtDhtService::with_options(BtDhtId::new(), &node_addr, &handle, options);
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()};
+    let started = Instant::now();
+
+    let (first, second) = core.run(node_service.service.call(dead_addr, ping.clone()).then(Ok::<_, ()>)
+                                   .join(node_service.service.call(dead_addr, ping).then(Ok::<_, ()>))).unwrap();
+
+    match (first, second) {
+        (Err(KTransError::Timeout), Err(KTransError::Timeout)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    // the second query is sent when the first one times out
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    dead_socket.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 256];
+    assert!(dead_socket.recv_from(&mut buf).is_ok());
+    assert!(dead_socket.recv_from(&mut buf).is_ok());
+}
+
+#[test]
+fn test_shutdown() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (_dead_socket, dead_addr) = silent_node();
+
+    let (node_service, node_server) = BtDhtService::new(BtDhtId::new(), &node_addr, &handle);
+    let (done_tx, done_rx) = oneshot::channel();
+
+    handle.spawn(node_server.then(|result| done_tx.send(result.is_ok()).map_err(|_| ())));
+
+    let ping = BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()};
+    let (pending_tx, pending_rx) = oneshot::channel();
+
+    handle.spawn(node_service.service.call(dead_addr, ping.clone())
+                 .then(|result| pending_tx.send(result).map_err(|_| ())));
+
+    // let server start the transaction
+    core.turn(Some(Duration::from_millis(10)));
+    core.turn(Some(Duration::from_millis(10)));
+    assert_eq!(node_service.service.active(), 1);
+
+    node_service.service.shutdown();
+
+    assert!(core.run(done_rx).unwrap());
+
+    match core.run(pending_rx).unwrap() {
+        Err(KTransError::Shutdown) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    match core.run(node_service.service.call(dead_addr, ping)) {
+        Err(KTransError::Shutdown) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_bind_error() {
+    let core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let (_busy_socket, busy_addr) = silent_node();
+
+    assert!(KService::bind(BtDhtHandler::new(BtDhtId::new()), &busy_addr, &handle, KOptions::default()).is_err());
+}
+
+#[test]
+fn test_options_error() {
+    let core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let hub = KMemoryHub::new();
+    let transport = hub.bind(&"10.0.0.1:6881".parse().unwrap()).unwrap();
+    let options = KOptions { tid_len: 0, ..KOptions::default() };
+
+    assert!(KService::with_transport(BtDhtHandler::new(BtDhtId::new()), transport, &handle, options).is_err());
+}
+
+#[test]
+fn test_from_socket() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node1_id = BtDhtId::new();
+    let node2_id = BtDhtId::new();
+
+    let (node1_service, node1_server) = KService::from_socket(BtDhtHandler::new(node1_id), silent_node().0, &handle, KOptions::default()).unwrap();
+    let (node2_service, node2_server) = KService::from_socket(BtDhtHandler::new(node2_id), silent_node().0, &handle, KOptions::default()).unwrap();
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    match core.run(node1_service.call(node2_service.local_addr(), BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+// Any code which is generic over service can wrap the outgoing calls
+fn ping_via<S>(service: &S, addr: SocketAddr, id: BtDhtId) -> S::Future
+    where S: Service<Request = (SocketAddr, BtDhtArg), Response = BtDhtRes, Error = KTransError>
+{
+    service.call((addr, BtDhtArg::Ping {id, extra: KExtra::new()}))
+}
+
+#[test]
+fn test_client_service() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let any_addr = "127.0.0.1:0".parse().unwrap();
+
+    let node1_id = BtDhtId::new();
+    let node2_id = BtDhtId::new();
+
+    let (node1_service, node1_server) = KService::bind(BtDhtHandler::new(node1_id), &any_addr, &handle, KOptions::default()).unwrap();
+    let (node2_service, node2_server) = KService::bind(BtDhtHandler::new(node2_id), &any_addr, &handle, KOptions::default()).unwrap();
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    match core.run(ping_via(&node1_service, node2_service.local_addr(), node1_id)) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[cfg(feature = "tower")]
+#[test]
+fn test_tower_backpressure() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let node_addr = "127.0.0.1:0".parse().unwrap();
+    let (_dead_socket, dead_addr) = silent_node();
+
+    let options = KOptions {
+        timeout: Duration::from_millis(100),
+        max_active: 1,
+        max_queued: 1,
+        ..KOptions::default()
+    };
+    let (node_service, node_server) = BtDhtService::with_options(BtDhtId::new(), &node_addr, &handle, options);
+    let mut service = node_service.service.clone();
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: node_service.node_id, extra: KExtra::new()};
+
+    // the first query is sent and the second one waits in queue
+    handle.spawn(service.call(dead_addr, ping.clone()).then(|_| Ok(())));
+    handle.spawn(service.call(dead_addr, ping).then(|_| Ok(())));
+    for _ in 0..4 {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+
+    let ready = core.run(poll_fn(|| Ok::<_, ()>(Async::Ready(tower_service::Service::poll_ready(&mut service).unwrap().is_ready())))).unwrap();
+    assert!(!ready);
+
+    // the queue is freed when the first query times out
+    core.run(poll_fn(|| tower_service::Service::poll_ready(&mut service))).unwrap();
+    assert_eq!(node_service.service.active(), 1);
+}
+
+#[test]
+fn test_sync_client() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let any_addr = "127.0.0.1:0".parse().unwrap();
+
+    let node1_id = BtDhtId::new();
+    let node2_id = BtDhtId::new();
+
+    let (node1_service, node1_server) = KService::bind(BtDhtHandler::new(node1_id), &any_addr, &handle, KOptions::default()).unwrap();
+    let (node2_service, node2_server) = KService::bind(BtDhtHandler::new(node2_id), &any_addr, &handle, KOptions::default()).unwrap();
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    let client = node1_service.sync_client();
+    let node2_addr = node2_service.local_addr();
+
+    let (res_tx, res_rx) = futures::sync::oneshot::channel();
+
+    thread::spawn(move || {
+        let _ = res_tx.send(client.call(node2_addr, BtDhtArg::Ping {id: node1_id, extra: KExtra::new()}).wait());
+    });
+
+    match core.run(res_rx).unwrap() {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_demux() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let any_addr = "127.0.0.1:0".parse().unwrap();
+
+    let (node_service, node_server) = KService::bind(BtDhtHandler::new(BtDhtId::new()), &any_addr, &handle, KOptions::default()).unwrap();
+
+    handle.spawn(node_server.map_err(|_| ()));
+
+    let (raw_sink, raw_stream) = node_service.demux();
+    // let server register the demultiplexer
+    core.turn(Some(Duration::from_millis(10)));
+
+    let (peer_socket, peer_addr) = silent_node();
+    peer_socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    peer_socket.send_to(b"\x41\x00utp", node_service.local_addr()).unwrap();
+
+    let (raw, _) = core.run(raw_stream.into_future().map_err(|_| ())).unwrap();
+    assert_eq!(raw, Some(KRaw(peer_addr, b"\x41\x00utp".to_vec())));
+
+    core.run(raw_sink.send(KRaw(peer_addr, b"\x21\x00utp".to_vec()))
+             .and_then(|_| Timeout::new(Duration::from_millis(10), &handle).unwrap().map_err(|_| unreachable!()))).unwrap();
+
+    let mut buf = [0u8; 16];
+    let (len, addr) = peer_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(addr, node_service.local_addr());
+    assert_eq!(&buf[..len], b"\x21\x00utp");
+}
+
+type BtDhtNode = (BtDhtId, KService<BtDhtQuery, BtDhtArg, BtDhtRes, BtDhtHandler>);
+
+/// Spawn two nodes with given options connected by memory hub at 10.0.0.1 and 10.0.1.1
+fn two_nodes(core: &Core, options: (KOptions, KOptions)) -> (KMemoryHub, BtDhtNode, BtDhtNode) {
+    let handle = core.handle();
+    let hub = KMemoryHub::new();
+
+    let node1_id = BtDhtId::new();
+    let node2_id = BtDhtId::new();
+
+    let node1_transport = hub.bind(&"10.0.0.1:6881".parse().unwrap()).unwrap();
+    let node2_transport = hub.bind(&"10.0.1.1:6881".parse().unwrap()).unwrap();
+
+    let (node1_service, node1_server) = KService::with_transport(BtDhtHandler::new(node1_id), node1_transport, &handle, options.0).unwrap();
+    let (node2_service, node2_server) = KService::with_transport(BtDhtHandler::new(node2_id), node2_transport, &handle, options.1).unwrap();
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    (hub, (node1_id, node1_service), (node2_id, node2_service))
+}
+
+#[test]
+fn test_memory_transport() {
+    let mut core = Core::new().unwrap();
+    let (_hub, (node1_id, node1_service), (node2_id, node2_service)) = two_nodes(&core, (KOptions::default(), KOptions::default()));
+
+    match core.run(node2_service.call(node1_service.local_addr(), BtDhtArg::Ping {id: node2_id, extra: KExtra::new()})) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node1_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+/// Drops messages of blocked node, replaces the id in responses and sends find_node queries as pings
+pub struct BtDhtPolicy {
+    blocked: SocketAddr,
+    node_id: BtDhtId,
+}
+
+impl KInterceptor<BtDhtArg, BtDhtRes> for BtDhtPolicy {
+    fn inbound_query(&mut self, item: KItem<BtDhtArg, BtDhtRes>) -> Option<KItem<BtDhtArg, BtDhtRes>> {
+        if (item.0).0 == self.blocked { None } else { Some(item) }
+    }
+
+    fn outbound_response(&mut self, item: KItem<BtDhtArg, BtDhtRes>) -> Option<KItem<BtDhtArg, BtDhtRes>> {
+        match item {
+            KItem(id, KData::Response(BtDhtRes::Pong {..}), meta) => Some(KItem(id, KData::Response(BtDhtRes::Pong {id: self.node_id, extra: KExtra::new()}), meta)),
+            item => Some(item),
+        }
+    }
+
+    fn outgoing_query(&mut self, item: KItem<BtDhtArg, BtDhtRes>) -> Option<KItem<BtDhtArg, BtDhtRes>> {
+        match item {
+            KItem(KId(addr, _), ..) if addr == self.blocked => None,
+            KItem(id, KData::Query(BtDhtArg::FindNode {id: node_id, extra, ..}), meta) => Some(KItem(id, KData::Query(BtDhtArg::Ping {id: node_id, extra}), meta)),
+            item => Some(item),
+        }
+    }
+}
+
+#[test]
+fn test_interceptor() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let options = KOptions { timeout: Duration::from_millis(200), ..KOptions::default() };
+    let (hub, (node1_id, node1_service), (node2_id, node2_service)) = two_nodes(&core, (options.clone(), options.clone()));
+
+    let node3_id = BtDhtId::new();
+    let node3_transport = hub.bind(&"10.0.2.1:6881".parse().unwrap()).unwrap();
+    let (node3_service, node3_server) = KService::with_transport(BtDhtHandler::new(node3_id), node3_transport, &handle, options).unwrap();
+    handle.spawn(node3_server.map_err(|_| ()));
+
+    let spoof_id = BtDhtId::new();
+    node1_service.intercept(BtDhtPolicy {blocked: node3_service.local_addr(), node_id: spoof_id});
+
+    // response is rewritten
+    match core.run(node2_service.call(node1_service.local_addr(), BtDhtArg::Ping {id: node2_id, extra: KExtra::new()})) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, spoof_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    // inbound query is dropped
+    match core.run(node3_service.call(node1_service.local_addr(), BtDhtArg::Ping {id: node3_id, extra: KExtra::new()})) {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    // outgoing query is dropped
+    match core.run(node1_service.call(node3_service.local_addr(), BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Err(KTransError::Rejected) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(node1_service.active(), 0);
+
+    // response is decoded for rewritten query
+    match core.run(node1_service.call(node2_service.local_addr(), BtDhtArg::FindNode {id: node1_id, target: node3_id, extra: KExtra::new()})) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    let stats = node1_service.stats();
+    assert_eq!(stats.queries["ping"].queries_sent, 1);
+    assert!(!stats.queries.contains_key("find_node"));
+}
+
+#[test]
+fn test_observer() {
+    let mut core = Core::new().unwrap();
+    let (_hub, (node1_id, node1_service), (_, node2_service)) = two_nodes(&core, (KOptions::default(), KOptions::default()));
+
+    let node1_observer = node1_service.observe(16);
+    let node2_observer = node2_service.observe(1);
+
+    core.run(node1_service.call(node2_service.local_addr(), BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})).unwrap();
+
+    let events = core.run(node1_observer.take(2).collect()).unwrap();
+    assert_eq!(events[0].direction, KDirection::Outbound);
+    assert_eq!(events[0].peer, node2_service.local_addr());
+    assert_eq!(events[0].kind, KEventKind::Query);
+    assert_eq!(events[0].query, Some("ping".into()));
+    assert_eq!(events[0].rtt, None);
+    assert_eq!(events[1].direction, KDirection::Inbound);
+    assert_eq!(events[1].tid, events[0].tid);
+    assert_eq!(events[1].kind, KEventKind::Response);
+    assert_eq!(events[1].query, Some("ping".into()));
+    assert!(events[1].rtt.is_some());
+
+    // inbound query is dropped in favor of outbound response
+    assert_eq!(node2_observer.lost(), 1);
+    let (event, _) = core.run(node2_observer.into_future()).map_err(|_| ()).unwrap();
+    let event = event.unwrap();
+    assert_eq!(event.direction, KDirection::Outbound);
+    assert_eq!(event.kind, KEventKind::Response);
+    assert_eq!(event.query, Some("ping".into()));
+}
+
+#[test]
+fn test_stats() {
+    let mut core = Core::new().unwrap();
+    let options = KOptions { timeout: Duration::from_millis(100), ..KOptions::default() };
+    let (hub, (node1_id, node1_service), (_, node2_service)) = two_nodes(&core, (options.clone(), options));
+    let raw_transport = hub.bind(&"10.0.0.3:6881".parse().unwrap()).unwrap();
+
+    core.run(node1_service.call(node2_service.local_addr(), BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})).unwrap();
+    match core.run(node1_service.call("10.0.0.4:6881".parse().unwrap(), BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    // the ping query without argument
+    let raw_transport = core.run(raw_transport.send(KRaw(node2_service.local_addr(), b"d1:q4:ping1:t2:aa1:y1:qe".to_vec()))).unwrap();
+    // the unknown methods is counted together
+    core.run(raw_transport.send(KRaw(node2_service.local_addr(), b"d1:q6:bogus11:t2:ab1:y1:qe".to_vec()))).unwrap();
+    core.turn(Some(Duration::from_millis(10)));
+
+    let stats = node1_service.stats();
+    let ping = &stats.queries["ping"];
+    assert_eq!(ping.queries_sent, 2);
+    assert_eq!(ping.responses_received, 1);
+    assert_eq!(ping.timeouts, 1);
+    assert_eq!(ping.latency.count, 1);
+
+    let stats = node2_service.stats();
+    let ping = &stats.queries["ping"];
+    assert_eq!(ping.queries_received, 1);
+    assert_eq!(ping.responses_sent, 1);
+    assert_eq!(ping.decode_errors, 1);
+    assert_eq!(stats.queries["unknown"].decode_errors, 1);
+    assert!(!stats.queries.contains_key("bogus1"));
+    assert_eq!(stats.decode_errors, 2);
+
+    assert!(stats.to_prometheus("krpc").contains("krpc_responses_sent_total{query=\"ping\"} 1\n"));
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut core = Core::new().unwrap();
+    let rate_limit = KRateLimit { ip_rate: 0.01, ip_burst: 1.0, over_limit: KOverLimit::Reply, ..KRateLimit::default() };
+    let options = (KOptions::default(), KOptions { rate_limit: Some(rate_limit), ..KOptions::default() });
+    let (_hub, (node1_id, node1_service), (node2_id, node2_service)) = two_nodes(&core, options);
+
+    let node2_addr = node2_service.local_addr();
+
+    match core.run(node1_service.call(node2_addr, BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match core.run(node1_service.call(node2_addr, BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Err(KTransError::KError(KError(KErrorKind::Server, _))) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    assert_eq!(node2_service.throttled_ips(), vec![(node1_service.local_addr().ip(), 1)]);
+    assert_eq!(node2_service.stats().throttled, 1);
+    // the throttled query is answered by service itself
+    assert_eq!(node2_service.stats().queries["ping"].errors_sent[&KErrorKind::Server], 1);
+}
+
+#[test]
+fn test_blocklist() {
+    let mut core = Core::new().unwrap();
+    let blocklist = KBlocklist::new();
+    let options = KOptions { timeout: Duration::from_millis(100), ..KOptions::default() };
+    let options = (options.clone(), KOptions { blocklist: Some(blocklist.clone()), ..options });
+    let (_hub, (node1_id, node1_service), (node2_id, node2_service)) = two_nodes(&core, options);
+
+    let node1_addr = node1_service.local_addr();
+    let node2_addr = node2_service.local_addr();
+
+    assert!(core.run(node1_service.call(node2_addr, BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})).is_ok());
+
+    // the list is updated while service is running
+    let update = KBlocklist::new();
+    update.load_p2p(&b"Test range:10.0.0.0-10.0.0.255\n"[..]).unwrap();
+    blocklist.replace(&update);
+
+    match core.run(node1_service.call(node2_addr, BtDhtArg::Ping {id: node1_id, extra: KExtra::new()})) {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match core.run(node2_service.call(node1_addr, BtDhtArg::Ping {id: node2_id, extra: KExtra::new()})) {
+        Err(KTransError::Blocked) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(node2_service.stats().blocked, 2);
+}
+
+/// Responds with node id of the family which query arrived on
+pub struct BtDhtDualHandler {
+    v4_id: BtDhtId,
+    v6_id: BtDhtId,
+}
+
+impl Service for BtDhtDualHandler {
+    type Request = (KFamily, BtDhtArg);
+    type Response = BtDhtRes;
+    type Error = KError;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, (family, _arg): Self::Request) -> Self::Future {
+        let id = match family {
+            KFamily::V4 => self.v4_id,
+            KFamily::V6 => self.v6_id,
+        };
+        Box::new(ok(BtDhtRes::Pong {id, extra: KExtra::new()}))
+    }
+}
+
+#[test]
+fn test_dual_stack() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let hub = KMemoryHub::new();
+
+    let node1_handler = BtDhtDualHandler {v4_id: BtDhtId::new(), v6_id: BtDhtId::new()};
+    let node2_handler = BtDhtDualHandler {v4_id: BtDhtId::new(), v6_id: BtDhtId::new()};
+    let (node2_v4_id, node2_v6_id) = (node2_handler.v4_id, node2_handler.v6_id);
+
+    let (node1_service, node1_server) = KDualService::with_transports(
+        node1_handler,
+        hub.bind(&"10.0.0.1:6881".parse().unwrap()).unwrap(),
+        hub.bind(&"[fd00::1]:6881".parse().unwrap()).unwrap(),
+        &handle, KOptions::default()).unwrap();
+    let (node2_service, node2_server) = KDualService::with_transports(
+        node2_handler,
+        hub.bind(&"10.0.0.2:6881".parse().unwrap()).unwrap(),
+        hub.bind(&"[fd00::2]:6881".parse().unwrap()).unwrap(),
+        &handle, KOptions::default()).unwrap();
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: BtDhtId::new(), extra: KExtra::new()};
+
+    match core.run(node1_service.call(node2_service.local_addr(KFamily::V4), ping.clone())) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_v4_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match core.run(node1_service.call(node2_service.local_addr(KFamily::V6), ping)) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_v6_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_dual_stack_bind() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    // find free port for the both families
+    let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+
+    let node1_handler = BtDhtDualHandler {v4_id: BtDhtId::new(), v6_id: BtDhtId::new()};
+    let node2_handler = BtDhtDualHandler {v4_id: BtDhtId::new(), v6_id: BtDhtId::new()};
+    let (node2_v4_id, node2_v6_id) = (node2_handler.v4_id, node2_handler.v6_id);
+
+    let (node1_service, node1_server) = KDualService::bind(node1_handler,
+        &"127.0.0.1:0".parse().unwrap(), &"[::1]:0".parse().unwrap(),
+        &handle, KOptions::default()).unwrap();
+    let (node2_service, node2_server) = KDualService::bind(node2_handler,
+        &SocketAddr::new("0.0.0.0".parse().unwrap(), port), &SocketAddr::new("::".parse().unwrap(), port),
+        &handle, KOptions::default()).unwrap();
+
+    assert_eq!(node2_service.local_addr(KFamily::V4).port(), port);
+    assert_eq!(node2_service.local_addr(KFamily::V6).port(), port);
+
+    handle.spawn(node1_server.map_err(|_| ()));
+    handle.spawn(node2_server.map_err(|_| ()));
+
+    let ping = BtDhtArg::Ping {id: BtDhtId::new(), extra: KExtra::new()};
+
+    match core.run(node1_service.call(SocketAddr::new("127.0.0.1".parse().unwrap(), port), ping.clone())) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_v4_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    match core.run(node1_service.call(SocketAddr::new("::1".parse().unwrap(), port), ping)) {
+        Ok(BtDhtRes::Pong {id, ..}) => assert_eq!(id, node2_v6_id),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
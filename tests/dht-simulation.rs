@@ -0,0 +1,229 @@
+#![feature(conservative_impl_trait)]
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate tokio_krpc;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use futures::{Future, Stream};
+use futures::future::{ok, err, join_all};
+
+use tokio_core::reactor::{Handle, Core};
+use tokio_service::Service;
+
+use tokio_krpc::{KError, KErrorKind, KExtra, KService, KOptions, KTransError, KSimNetwork, KSimOptions, KSimStats};
+use tokio_krpc::dht::bittorrent::{BtDhtId, BtDhtQuery, BtDhtArg, BtDhtRes};
+
+#[derive(Clone)]
+pub struct BtDhtHandler {
+    node_id: BtDhtId,
+}
+
+impl Service for BtDhtHandler {
+    type Request = BtDhtArg;
+    type Response = BtDhtRes;
+    type Error = KError;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, arg: Self::Request) -> Self::Future {
+        Box::new(match arg {
+            BtDhtArg::Ping {..} => ok(BtDhtRes::Pong {id: self.node_id, extra: KExtra::new()}),
+            _ => err(KError(KErrorKind::Method, "Method unimplemented".into())),
+        })
+    }
+}
+
+type BtDhtService = KService<BtDhtQuery, BtDhtArg, BtDhtRes, BtDhtHandler>;
+
+struct BtDhtNode {
+    id: BtDhtId,
+    addr: SocketAddr,
+    service: BtDhtService,
+}
+
+fn node_ip(index: usize) -> IpAddr {
+    let n = index + 1;
+    IpAddr::V4(Ipv4Addr::new(10, (n >> 16) as u8, (n >> 8) as u8, n as u8))
+}
+
+fn spawn_nodes(network: &KSimNetwork, count: usize, handle: &Handle) -> Vec<BtDhtNode> {
+    let options = KOptions { timeout: Duration::from_millis(200), ..KOptions::default() };
+    (0..count).map(|index| {
+        let id = BtDhtId::new();
+        let transport = network.bind(&SocketAddr::new(node_ip(index), 6881)).unwrap();
+        let (service, server) = KService::with_transport(BtDhtHandler {node_id: id}, transport, handle, options.clone()).unwrap();
+        handle.spawn(server.map_err(|_| ()));
+        BtDhtNode {id, addr: service.local_addr(), service}
+    }).collect()
+}
+
+fn ping(from: &BtDhtNode, to: &BtDhtNode) -> impl Future<Item = Result<BtDhtId, KTransError>, Error = ()> {
+    from.service.call(to.addr, BtDhtArg::Ping {id: from.id, extra: KExtra::new()})
+        .map(|res| match res {
+            BtDhtRes::Pong {id, ..} => id,
+            res => panic!("Unexpected response: {:?}", res),
+        })
+        .then(Ok)
+}
+
+#[test]
+fn test_sim_many_nodes() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let options = KSimOptions {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(10),
+        ..KSimOptions::default()
+    };
+    let network = KSimNetwork::new(1, options);
+    let nodes = spawn_nodes(&network, 500, &handle);
+
+    // every node pings the next one at once
+    let results = network.run(&mut core, join_all(nodes.iter().enumerate().map(|(index, node)| {
+        ping(node, &nodes[(index + 1) % nodes.len()])
+    }).collect::<Vec<_>>())).unwrap();
+
+    for (index, result) in results.into_iter().enumerate() {
+        assert_eq!(result.unwrap(), nodes[(index + 1) % nodes.len()].id);
+    }
+
+    let stats = network.stats();
+    assert_eq!(stats.sent, 1000);
+    assert_eq!(stats.delivered, 1000);
+}
+
+#[test]
+fn test_sim_partition() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let network = KSimNetwork::new(1, KSimOptions::default());
+    let nodes = spawn_nodes(&network, 3, &handle);
+
+    network.partition(&[nodes[0].addr.ip()]);
+
+    match network.run(&mut core, ping(&nodes[1], &nodes[0])).unwrap() {
+        Err(KTransError::Timeout) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    // the call is timed out by virtual clock
+    assert_eq!(network.now(), Duration::from_millis(200));
+    assert_eq!(network.run(&mut core, ping(&nodes[1], &nodes[2])).unwrap().unwrap(), nodes[2].id);
+    assert_eq!(network.now(), Duration::from_millis(220));
+
+    network.heal();
+
+    assert_eq!(network.run(&mut core, ping(&nodes[1], &nodes[0])).unwrap().unwrap(), nodes[0].id);
+    assert_eq!(network.stats().partitioned, 1);
+}
+
+fn lossy_options() -> KSimOptions {
+    KSimOptions {
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(5),
+        loss: 0.3,
+        reorder: 0.2,
+    }
+}
+
+// Sequential pings over lossy network
+fn sequential_scenario(seed: u32) -> Vec<bool> {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let network = KSimNetwork::new(seed, lossy_options());
+    let nodes = spawn_nodes(&network, 10, &handle);
+
+    (0..20).map(|index| {
+        let from = &nodes[index % nodes.len()];
+        let to = &nodes[(index * 7 + 3) % nodes.len()];
+        network.run(&mut core, ping(from, to)).unwrap().is_ok()
+    }).collect()
+}
+
+// Concurrent pings over lossy network, gives results with the time and counters at the end
+fn concurrent_scenario(seed: u32) -> (Vec<bool>, Duration, KSimStats) {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let network = KSimNetwork::new(seed, lossy_options());
+    let nodes = spawn_nodes(&network, 20, &handle);
+
+    // every node pings a few others at once
+    let results = network.run(&mut core, join_all((0..60).map(|index| {
+        let from = &nodes[index % nodes.len()];
+        let to = &nodes[(index * 7 + 3) % nodes.len()];
+        ping(from, to)
+    }).collect::<Vec<_>>())).unwrap();
+
+    (results.into_iter().map(|result| result.is_ok()).collect(), network.now(), network.stats())
+}
+
+#[test]
+fn test_sim_deterministic() {
+    let results = sequential_scenario(42);
+
+    assert!(results.iter().any(|ok| *ok));
+    assert!(results.iter().any(|ok| !*ok));
+    assert_eq!(sequential_scenario(42), results);
+}
+
+#[test]
+fn test_sim_concurrent_deterministic() {
+    let (results, now, stats) = concurrent_scenario(42);
+
+    assert!(results.iter().any(|ok| *ok));
+    assert!(results.iter().any(|ok| !*ok));
+    assert!(stats.lost > 0);
+    assert!(stats.reordered > 0);
+    assert_eq!(concurrent_scenario(42), (results, now, stats));
+}
+
+#[test]
+fn test_sim_advance() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let network = KSimNetwork::new(1, KSimOptions::default());
+    let nodes = spawn_nodes(&network, 2, &handle);
+
+    let result = Rc::new(RefCell::new(None));
+    let slot = result.clone();
+    handle.spawn(ping(&nodes[0], &nodes[1]).map(move |result| *slot.borrow_mut() = Some(result)));
+
+    // the response isn't received until the clock is moved by round trip
+    network.advance(&mut core, Duration::from_millis(19));
+    assert!(result.borrow().is_none());
+    assert_eq!(network.stats().delivered, 1);
+
+    network.advance(&mut core, Duration::from_millis(1));
+    assert_eq!(result.borrow_mut().take().unwrap().unwrap(), nodes[1].id);
+    assert_eq!(network.now(), Duration::from_millis(20));
+}
+
+#[test]
+fn test_sim_external_addr() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let network = KSimNetwork::new(1, KSimOptions::default());
+    let nodes = spawn_nodes(&network, 4, &handle);
+    let changes = nodes[0].service.external_addr_changes();
+
+    for node in &nodes[1..3] {
+        network.run(&mut core, ping(&nodes[0], node)).unwrap().unwrap();
+    }
+    // the quorum isn't reached yet
+    assert_eq!(nodes[0].service.external_addr(), None);
+
+    network.run(&mut core, ping(&nodes[0], &nodes[3])).unwrap().unwrap();
+    assert_eq!(nodes[0].service.external_addr(), Some(nodes[0].addr));
+
+    let (addr, _) = network.run(&mut core, changes.into_future()).map_err(|_| ()).unwrap();
+    assert_eq!(addr, Some(nodes[0].addr));
+}